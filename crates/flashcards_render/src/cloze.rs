@@ -0,0 +1,361 @@
+//! Expansion of cloze deletions such as `{{c1::answer}}` or
+//! `{{c1::answer::hint}}` into one term and definition per cloze index.
+//!
+//! The term blanks out every deletion with the current index and the
+//! definition highlights them, while deletions with other indices are shown
+//! as plain text on both sides. The markup used depends on the format, and on
+//! whether the deletion is inside math.
+
+use crate::{Format, Source};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unclosed cloze deletion starting at byte {0}")]
+    Unclosed(usize),
+    #[error("invalid cloze index at byte {0}")]
+    InvalidIndex(usize),
+    #[error("cloze card must contain at least one deletion")]
+    NoDeletions,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Segment<'a> {
+    Text(&'a str),
+    Deletion {
+        index: u32,
+        answer: &'a str,
+        hint: Option<&'a str>,
+        in_math: bool,
+    },
+}
+
+const OPEN: &str = "{{c";
+const SEPARATOR: &str = "::";
+const CLOSE: &str = "}}";
+
+/// Find the end of an answer or hint, skipping over balanced braces so that
+/// TeX such as `\frac{1}{2}` can be used inside a deletion.
+fn find_end(input: &str) -> Option<(usize, bool)> {
+    let mut depth = 0usize;
+    let mut chars = input.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '{' => depth += 1,
+            '}' if depth == 0 => {
+                return input[i..].starts_with(CLOSE).then_some((i, true));
+            }
+            '}' => depth -= 1,
+            ':' if depth == 0 && input[i..].starts_with(SEPARATOR) => return Some((i, false)),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Track whether the end of TeX or Typst `text` is inside `$...$` or
+/// `$$...$$`, starting from `in_math`.
+fn ends_in_math(text: &str, mut in_math: bool) -> bool {
+    let mut chars = text.chars().peekable();
+    let mut block = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '$' if chars.peek() == Some(&'$') => {
+                chars.next();
+                block = !block;
+                in_math = block;
+            }
+            '$' if !block => in_math = !in_math,
+            _ => {}
+        }
+    }
+
+    in_math
+}
+
+fn parse(source: &Source) -> Result<Vec<Segment<'_>>, Error> {
+    let text = source.source.as_str();
+    let mut segments = Vec::new();
+    let mut position = 0;
+    let mut in_math = false;
+
+    // whether a dollar starts math in Markdown depends on what is around it,
+    // and math is never in code, so it is found the way it is when rendering
+    let markdown_math = (source.format == Format::Markdown)
+        .then(|| crate::render::markdown_math(text, &source.config.markdown));
+
+    while let Some(start) = text[position..].find(OPEN).map(|i| i + position) {
+        let before = &text[position..start];
+        in_math = match &markdown_math {
+            Some(math) => math.iter().any(|range| range.contains(&start)),
+            None => ends_in_math(before, in_math),
+        };
+        segments.push(Segment::Text(before));
+
+        let digits_start = start + OPEN.len();
+        let digits_len = text[digits_start..]
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(text.len() - digits_start);
+        let index = text[digits_start..digits_start + digits_len]
+            .parse::<u32>()
+            .map_err(|_| Error::InvalidIndex(start))?;
+
+        let answer_start = digits_start + digits_len;
+        if !text[answer_start..].starts_with(SEPARATOR) {
+            return Err(Error::InvalidIndex(start));
+        }
+        let answer_start = answer_start + SEPARATOR.len();
+
+        let (answer_len, closed) = find_end(&text[answer_start..]).ok_or(Error::Unclosed(start))?;
+        let answer = &text[answer_start..answer_start + answer_len];
+
+        let (hint, end) = if closed {
+            (None, answer_start + answer_len + CLOSE.len())
+        } else {
+            let hint_start = answer_start + answer_len + SEPARATOR.len();
+            let (hint_len, closed) = find_end(&text[hint_start..]).ok_or(Error::Unclosed(start))?;
+            if !closed {
+                return Err(Error::Unclosed(start));
+            }
+            (
+                Some(&text[hint_start..hint_start + hint_len]),
+                hint_start + hint_len + CLOSE.len(),
+            )
+        };
+
+        segments.push(Segment::Deletion {
+            index,
+            answer,
+            hint,
+            in_math,
+        });
+        in_math = ends_in_math(answer, in_math);
+        position = end;
+    }
+
+    segments.push(Segment::Text(&text[position..]));
+    Ok(segments)
+}
+
+fn blank(format: Format, answer: &str, hint: Option<&str>, in_math: bool) -> String {
+    match (format, in_math) {
+        (Format::Markdown, false) => {
+            let mut escaped = String::new();
+            pulldown_cmark_escape::escape_html(&mut escaped, hint.unwrap_or("…")).unwrap();
+            format!(r#"<span class="cloze">[{escaped}]</span>"#)
+        }
+        (Format::Markdown | Format::Tex, true) => match hint {
+            Some(hint) => format!(r"\underline{{\text{{{hint}}}}}"),
+            None => format!(r"\underline{{\phantom{{{answer}}}}}"),
+        },
+        (Format::Tex, false) => match hint {
+            Some(hint) => format!(r"\underline{{{hint}}}"),
+            None => format!(r"\underline{{\phantom{{{answer}}}}}"),
+        },
+        (Format::Typst, false) => match hint {
+            Some(hint) => format!("#box(stroke: (bottom: 0.5pt))[{hint}]"),
+            None => format!("#box(stroke: (bottom: 0.5pt))[#hide[{answer}]]"),
+        },
+        (Format::TypstHtml, false) => format!(
+            r#"#html.elem("span", attrs: (class: "cloze"))[\[{}\]]"#,
+            hint.unwrap_or("…")
+        ),
+        (Format::Typst | Format::TypstHtml, true) => match hint {
+            Some(hint) => format!("underline(#[{hint}])"),
+            None => format!("underline(hide({answer}))"),
+        },
+    }
+}
+
+fn highlight(format: Format, answer: &str, in_math: bool) -> String {
+    match (format, in_math) {
+        (Format::Markdown, false) => format!(r#"<mark class="cloze">{answer}</mark>"#),
+        (Format::Markdown, true) => format!(r"\textcolor{{#e11d48}}{{{answer}}}"),
+        (Format::Tex, _) => format!(r"\textcolor{{red}}{{{answer}}}"),
+        (Format::Typst, false) => format!("#text(fill: red)[{answer}]"),
+        (Format::TypstHtml, false) => {
+            format!(r#"#html.elem("mark", attrs: (class: "cloze"))[{answer}]"#)
+        }
+        (Format::Typst | Format::TypstHtml, true) => format!("#text(fill: red)[${answer}$]"),
+    }
+}
+
+/// Expand a cloze source into `(index, term, definition)` for each cloze index, in order.
+pub fn expand(source: &Source) -> Result<Vec<(u32, Source, Source)>, Error> {
+    let segments = parse(source)?;
+
+    let indices = segments
+        .iter()
+        .filter_map(|segment| match segment {
+            Segment::Deletion { index, .. } => Some(*index),
+            Segment::Text(_) => None,
+        })
+        .collect::<BTreeSet<_>>();
+
+    if indices.is_empty() {
+        return Err(Error::NoDeletions);
+    }
+
+    let side = |current: u32, front: bool| {
+        let text = segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.to_string(),
+                Segment::Deletion { index, answer, .. } if *index != current => answer.to_string(),
+                Segment::Deletion {
+                    answer,
+                    hint,
+                    in_math,
+                    ..
+                } if front => blank(source.format, answer, *hint, *in_math),
+                Segment::Deletion {
+                    answer, in_math, ..
+                } => highlight(source.format, answer, *in_math),
+            })
+            .collect::<String>();
+
+        Source {
+            source: text,
+            format: source.format,
+            config: Arc::clone(&source.config),
+        }
+    };
+
+    Ok(indices
+        .into_iter()
+        .map(|index| (index, side(index, true), side(index, false)))
+        .collect())
+}
+
+#[test]
+fn cloze_expands_per_index() {
+    let source = Source {
+        source: "The {{c1::mitochondria}} is the {{c2::powerhouse::noun}} of ${{c1::x^{2}}}$"
+            .to_string(),
+        format: Format::Markdown,
+        config: Arc::default(),
+    };
+
+    let cards = expand(&source).unwrap();
+    assert_eq!(cards.len(), 2);
+
+    let (index, term, definition) = &cards[0];
+    assert_eq!(*index, 1);
+    assert_eq!(
+        term.source,
+        r#"The <span class="cloze">[…]</span> is the powerhouse of $\underline{\phantom{x^{2}}}$"#
+    );
+    assert_eq!(
+        definition.source,
+        r#"The <mark class="cloze">mitochondria</mark> is the powerhouse of $\textcolor{#e11d48}{x^{2}}$"#
+    );
+
+    // KaTeX typesets the blank and the highlight, rather than them being left as text
+    let options = crate::MarkdownOptions::default();
+    let term = crate::render::markdown(&term.source, &options).unwrap();
+    assert!(term.contains(r#"<span class="cloze">[…]</span>"#));
+    assert!(term.contains("underline-line") && term.contains("color:transparent"));
+    assert!(!term.contains('$'));
+    let definition = crate::render::markdown(&definition.source, &options).unwrap();
+    assert!(definition.contains(r#"<mark class="cloze">mitochondria</mark>"#));
+    assert!(definition.contains("color:#e11d48"));
+    assert!(!definition.contains('$'));
+
+    let (index, term, _) = &cards[1];
+    assert_eq!(*index, 2);
+    assert_eq!(
+        term.source,
+        r#"The mitochondria is the <span class="cloze">[noun]</span> of $x^{2}$"#
+    );
+}
+
+#[test]
+fn cloze_markdown_math_is_found_like_pulldown_cmark() {
+    let expand_markdown = |text: &str| {
+        let source = Source {
+            source: text.to_string(),
+            format: Format::Markdown,
+            config: Arc::default(),
+        };
+        let (_, term, definition) = expand(&source).unwrap().remove(0);
+        let options = crate::MarkdownOptions::default();
+        (
+            crate::render::markdown(&term.source, &options).unwrap(),
+            crate::render::markdown(&definition.source, &options).unwrap(),
+        )
+    };
+
+    // a price is not the start of math
+    let (term, definition) = expand_markdown("It costs $5 for a {{c1::coffee}}");
+    assert_eq!(
+        term,
+        "<p>It costs $5 for a <span class=\"cloze\">[…]</span></p>\n"
+    );
+    assert_eq!(
+        definition,
+        "<p>It costs $5 for a <mark class=\"cloze\">coffee</mark></p>\n"
+    );
+
+    // nor is a dollar in code
+    let (term, _) = expand_markdown("Set `$PATH` to {{c1::/usr/bin}}");
+    assert_eq!(
+        term,
+        "<p>Set <code>$PATH</code> to <span class=\"cloze\">[…]</span></p>\n"
+    );
+
+    // nor a dollar followed by a space
+    let (term, definition) = expand_markdown("$ {{c1::x}} $");
+    assert_eq!(term, "<p>$ <span class=\"cloze\">[…]</span> $</p>\n");
+    assert_eq!(definition, "<p>$ <mark class=\"cloze\">x</mark> $</p>\n");
+}
+
+#[test]
+fn cloze_in_typst_html() {
+    let source = Source {
+        source: "The {{c1::mitochondria::organelle}} of $ {{c1::x}} $".to_string(),
+        format: Format::TypstHtml,
+        config: Arc::default(),
+    };
+
+    let (_, term, definition) = &expand(&source).unwrap()[0];
+    assert_eq!(
+        term.source,
+        r#"The #html.elem("span", attrs: (class: "cloze"))[\[organelle\]] of $ underline(hide(x)) $"#
+    );
+    assert_eq!(
+        definition.source,
+        r#"The #html.elem("mark", attrs: (class: "cloze"))[mitochondria] of $ #text(fill: red)[$x$] $"#
+    );
+}
+
+#[test]
+fn cloze_errors() {
+    let source = |text: &str| Source {
+        source: text.to_string(),
+        format: Format::Tex,
+        config: Arc::default(),
+    };
+
+    assert!(matches!(
+        expand(&source("no deletions")),
+        Err(Error::NoDeletions)
+    ));
+    assert!(matches!(
+        expand(&source("{{c1::open")),
+        Err(Error::Unclosed(0))
+    ));
+    assert!(matches!(
+        expand(&source("{{cx::a}}")),
+        Err(Error::InvalidIndex(0))
+    ));
+}
@@ -1,10 +1,13 @@
-use crate::{Card, Format, Source, Topic};
-use serde::de::Error;
-use serde::{Deserialize, Deserializer};
+use crate::{Card, CardKind, Format, Source, Topic, cloze};
+use itertools::Itertools;
 use std::collections::HashSet;
 use std::convert::Infallible;
+use std::fmt::Display;
+use std::ops::Range;
 use std::str::FromStr;
 use std::sync::Arc;
+use toml::Spanned;
+use toml::de::{DeTable, DeValue};
 
 impl FromStr for Topic {
     type Err = Infallible;
@@ -26,6 +29,14 @@ impl Topic {
         Self(segments)
     }
 
+    pub fn prepend(&self, segment: Arc<str>) -> Self {
+        Self(
+            std::iter::once(segment)
+                .chain(self.0.iter().cloned())
+                .collect(),
+        )
+    }
+
     pub fn basename(&self) -> &str {
         self.0.last().expect("topics have at least 1 component")
     }
@@ -35,20 +46,65 @@ impl Topic {
     }
 }
 
-pub fn parse(source: &str) -> Result<Vec<Card<Source>>, toml::de::Error> {
-    let mut table = source.parse::<toml::Table>()?;
+/// An error in a deck file, with the byte range of the offending value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub message: String,
+    pub span: Option<Range<usize>>,
+}
 
-    let topics = parse_topics(&mut table)?;
+impl Error {
+    fn new(message: impl Display, span: Range<usize>) -> Self {
+        Self {
+            message: message.to_string(),
+            span: Some(span),
+        }
+    }
+}
 
-    let mut cards = match table.remove("cards") {
-        Some(toml::Value::Array(cards)) => cards
-            .into_iter()
-            .map(parse_card)
-            .collect::<Result<Vec<_>, _>>()?,
-        None => Vec::new(),
-        Some(_) => return Err(Error::custom("missing cards")),
+impl From<toml::de::Error> for Error {
+    fn from(err: toml::de::Error) -> Self {
+        Self {
+            message: err.message().to_string(),
+            span: err.span(),
+        }
+    }
+}
+
+type Value<'i> = Spanned<DeValue<'i>>;
+
+/// Parse the cards in a deck file, returning every error found in it.
+pub fn parse(source: &str) -> Result<Vec<Card<Source>>, Vec<Error>> {
+    let mut table = DeTable::parse(source)
+        .map_err(|err| vec![Error::from(err)])?
+        .into_inner();
+
+    let topics = parse_topics(&mut table).map_err(|err| vec![err])?;
+    let reverse = parse_reverse(&mut table)
+        .map_err(|err| vec![err])?
+        .map(Spanned::into_inner);
+
+    let cards = match table.remove("cards") {
+        Some(cards) => {
+            let span = cards.span();
+            match cards.into_inner() {
+                DeValue::Array(cards) => cards,
+                _ => return Err(vec![Error::new("cards must be an array of tables", span)]),
+            }
+        }
+        None => return Ok(Vec::new()),
     };
 
+    let (cards, errors): (Vec<_>, Vec<_>) = cards
+        .into_iter()
+        .map(|card| parse_card(card, reverse))
+        .partition_result();
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut cards = cards.into_iter().flatten().collect_vec();
     for card in cards.iter_mut() {
         card.topics.extend(topics.iter().cloned());
     }
@@ -56,48 +112,133 @@ pub fn parse(source: &str) -> Result<Vec<Card<Source>>, toml::de::Error> {
     Ok(cards)
 }
 
-fn parse_card(card: toml::Value) -> Result<Card<Source>, toml::de::Error> {
-    let toml::Value::Table(mut card) = card else {
-        return Err(Error::custom("card must be a table"));
-    };
+#[derive(Debug, Default)]
+enum Kind {
+    #[default]
+    Basic,
+    Cloze,
+}
+
+fn parse_kind(kind: Value) -> Result<Kind, Error> {
+    match kind.get_ref() {
+        DeValue::String(name) if name == "basic" => Ok(Kind::Basic),
+        DeValue::String(name) if name == "cloze" => Ok(Kind::Cloze),
+        DeValue::String(name) => Err(Error::new(
+            format!("unknown card kind `{name}`, expected `basic` or `cloze`"),
+            kind.span(),
+        )),
+        _ => Err(Error::new("card kind must be a string", kind.span())),
+    }
+}
 
-    let term = card
-        .remove("term")
-        .map(Source::deserialize)
-        .unwrap_or_else(|| Err(Error::custom("card must have a term")))?;
+fn parse_card(card: Value, default_reverse: Option<bool>) -> Result<Vec<Card<Source>>, Error> {
+    let span = card.span();
+    let DeValue::Table(mut card) = card.into_inner() else {
+        return Err(Error::new("card must be a table", span));
+    };
 
-    let definition = card
-        .remove("definition")
-        .map(Source::deserialize)
-        .unwrap_or_else(|| Err(Error::custom("card must have a definition")))?;
+    let kind = card
+        .remove("kind")
+        .map(parse_kind)
+        .transpose()?
+        .unwrap_or_default();
 
     let topics = parse_topics(&mut card)?;
+    let reverse = parse_reverse(&mut card)?;
+
+    match kind {
+        Kind::Basic => {
+            let term = card
+                .remove("term")
+                .map(parse_source)
+                .unwrap_or_else(|| Err(Error::new("card must have a term", span.clone())))?;
+
+            let definition = card
+                .remove("definition")
+                .map(parse_source)
+                .unwrap_or_else(|| Err(Error::new("card must have a definition", span.clone())))?;
 
-    Ok(Card {
-        term,
-        definition,
-        topics,
-    })
+            let reversed = reverse
+                .map(|reverse| *reverse.get_ref())
+                .or(default_reverse)
+                .unwrap_or(false)
+                .then(|| Card {
+                    term: definition.clone(),
+                    definition: term.clone(),
+                    topics: topics.clone(),
+                    kind: CardKind::Reversed,
+                });
+
+            Ok(std::iter::once(Card {
+                term,
+                definition,
+                topics,
+                kind: CardKind::Basic,
+            })
+            .chain(reversed)
+            .collect())
+        }
+        Kind::Cloze => {
+            if let Some(reverse) = reverse
+                && *reverse.get_ref()
+            {
+                return Err(Error::new("cloze cards cannot be reversed", reverse.span()));
+            }
+
+            let text = card
+                .remove("text")
+                .ok_or_else(|| Error::new("cloze card must have text", span.clone()))?;
+            let text_span = text.span();
+            let text = parse_source(text)?;
+
+            let cards = cloze::expand(&text).map_err(|err| Error::new(err, text_span))?;
+
+            Ok(cards
+                .into_iter()
+                .map(|(index, term, definition)| Card {
+                    term,
+                    definition,
+                    topics: topics.clone(),
+                    kind: CardKind::Cloze(index),
+                })
+                .collect())
+        }
+    }
+}
+
+fn parse_reverse(table: &mut DeTable) -> Result<Option<Spanned<bool>>, Error> {
+    let Some(reverse) = table.remove("reverse") else {
+        return Ok(None);
+    };
+
+    match reverse.get_ref() {
+        DeValue::Boolean(value) => Ok(Some(Spanned::new(reverse.span(), *value))),
+        _ => Err(Error::new("reverse must be a boolean", reverse.span())),
+    }
 }
 
-fn parse_topics(table: &mut toml::Table) -> Result<HashSet<Arc<Topic>>, toml::de::Error> {
+fn parse_topics(table: &mut DeTable) -> Result<HashSet<Arc<Topic>>, Error> {
     let mut topics = HashSet::new();
 
     let Some(topic_strs) = table.remove("topics") else {
         return Ok(topics);
     };
 
-    let toml::Value::Array(topic_strs) = topic_strs else {
-        return Err(Error::custom("topic array must be an array"));
+    let span = topic_strs.span();
+    let DeValue::Array(topic_strs) = topic_strs.into_inner() else {
+        return Err(Error::new("topic array must be an array", span));
     };
 
     for topic_str in topic_strs {
-        let toml::Value::String(topic_str) = topic_str else {
-            return Err(Error::custom("topic array must contain only strings"));
+        let DeValue::String(topic) = topic_str.get_ref() else {
+            return Err(Error::new(
+                "topic array must contain only strings",
+                topic_str.span(),
+            ));
         };
 
         topics.extend(
-            topic_str
+            topic
                 .parse::<Topic>()
                 .unwrap_or_else(|val| match val {})
                 .ancestors()
@@ -108,32 +249,129 @@ fn parse_topics(table: &mut toml::Table) -> Result<HashSet<Arc<Topic>>, toml::de
     Ok(topics)
 }
 
-impl<'de> Deserialize<'de> for Source {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let value = toml::Value::deserialize(deserializer)?;
-        match value {
-            toml::Value::String(source) => Ok(Self {
+fn parse_format(format: Value) -> Result<Format, Error> {
+    match format.get_ref() {
+        DeValue::String(name) => name.parse().map_err(|_| {
+            Error::new(
+                format!(
+                    "unknown format `{name}`, expected `markdown`, `tex`, `typst` or `typst-html`"
+                ),
+                format.span(),
+            )
+        }),
+        _ => Err(Error::new("format must be a string", format.span())),
+    }
+}
+
+fn parse_source(side: Value) -> Result<Source, Error> {
+    let span = side.span();
+    match side.into_inner() {
+        DeValue::String(source) => Ok(Source {
+            source: source.into_owned(),
+            format: Format::Markdown,
+            config: Arc::default(),
+        }),
+        DeValue::Table(mut table) => {
+            let format = table
+                .remove("format")
+                .map(parse_format)
+                .transpose()?
+                .unwrap_or_default();
+
+            let source = match table.remove("text") {
+                Some(text) => match text.get_ref() {
+                    DeValue::String(source) => source.to_string(),
+                    _ => return Err(Error::new("text must be a string", text.span())),
+                },
+                None => return Err(Error::new("missing text", span)),
+            };
+
+            Ok(Source {
                 source,
-                format: Format::Markdown,
-            }),
-            toml::Value::Table(mut table) => {
-                let format = table
-                    .remove("format")
-                    .map(|format| Format::deserialize(format).map_err(Error::custom))
-                    .transpose()?
-                    .unwrap_or(Format::default());
-
-                let source = match table.remove("text") {
-                    Some(source) => Deserialize::deserialize(source).map_err(Error::custom),
-                    None => return Err(Error::custom("missing text")),
-                }?;
-
-                Ok(Self { source, format })
-            }
-            _ => Err(Error::custom("invalid side of card")),
+                format,
+                config: Arc::default(),
+            })
         }
+        _ => Err(Error::new("invalid side of card", span)),
     }
 }
+
+#[test]
+fn parse_cloze_card() {
+    let cards = parse(
+        r#"
+        [[cards]]
+        kind = "cloze"
+        text = "The {{c1::mitochondria}} is the {{c2::powerhouse}} of the cell"
+        topics = ["biology/cells"]
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        cards.iter().map(|card| card.kind).collect::<Vec<_>>(),
+        [CardKind::Cloze(1), CardKind::Cloze(2)]
+    );
+    assert!(cards.iter().all(|card| card.topics.len() == 2));
+}
+
+#[test]
+fn parse_reversed_card() {
+    let cards = parse(
+        r#"
+        reverse = true
+
+        [[cards]]
+        term = "chat"
+        definition = "cat"
+
+        [[cards]]
+        term = "chien"
+        definition = "dog"
+        reverse = false
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        cards
+            .iter()
+            .map(|card| (
+                card.term.source.as_str(),
+                card.definition.source.as_str(),
+                card.kind
+            ))
+            .collect::<Vec<_>>(),
+        [
+            ("chat", "cat", CardKind::Basic),
+            ("cat", "chat", CardKind::Reversed),
+            ("chien", "dog", CardKind::Basic),
+        ]
+    );
+}
+
+#[test]
+fn parse_errors_have_spans() {
+    let source = r#"
+[[cards]]
+term = "a"
+definition = 1
+
+[[cards]]
+definition = "b"
+"#;
+
+    let errors = parse(source).unwrap_err();
+    assert_eq!(
+        errors
+            .iter()
+            .map(|err| err.message.as_str())
+            .collect::<Vec<_>>(),
+        ["invalid side of card", "card must have a term"]
+    );
+    assert_eq!(&source[errors[0].span.clone().unwrap()], "1");
+    assert_eq!(
+        errors[1].span.clone().unwrap().start,
+        source.rfind("[[cards]]").unwrap()
+    );
+}
@@ -0,0 +1,203 @@
+//! Stable content hashing.
+//!
+//! Hashes computed here are stored in the database as primary keys and
+//! appear in URLs, so unlike [`std::hash::DefaultHasher`] they must not change
+//! between Rust releases. Each hash is the first 8 bytes of a SHA-256 digest,
+//! read as a big-endian [`i64`], over a canonical encoding of its input:
+//!
+//! - the hash version and a domain (e.g. `"source"` or `"topic"`), so that
+//!   different kinds of value never share an encoding
+//! - every field in a fixed order, with strings and byte slices prefixed by
+//!   their length as a little-endian [`u64`] and integers written as 8
+//!   little-endian bytes
+//!
+//! Any change to an encoding must bump [`HASH_VERSION`] so that existing
+//! databases can be rekeyed.
+
+use crate::{Asset, Format, Source, Topic};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+/// Version of the encodings used by [`ContentHasher`].
+pub const HASH_VERSION: i64 = 1;
+
+#[derive(Debug, Clone)]
+pub struct ContentHasher(Sha256);
+
+impl ContentHasher {
+    pub fn new(domain: &str) -> Self {
+        let mut hasher = Self(Sha256::new());
+        hasher.write_i64(HASH_VERSION);
+        hasher.write_str(domain);
+        hasher
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.0.update((bytes.len() as u64).to_le_bytes());
+        self.0.update(bytes);
+    }
+
+    pub fn write_str(&mut self, s: &str) {
+        self.write_bytes(s.as_bytes());
+    }
+
+    pub fn write_i64(&mut self, n: i64) {
+        self.0.update(n.to_le_bytes());
+    }
+
+    pub fn finish(self) -> i64 {
+        let digest = self.0.finalize();
+        i64::from_be_bytes(
+            digest[..8]
+                .try_into()
+                .expect("digest to be at least 8 bytes"),
+        )
+    }
+}
+
+impl Source {
+    /// The hash of the format and text of this source.
+    pub fn content_hash(&self) -> i64 {
+        let mut hasher = ContentHasher::new("source");
+        hasher.write_str(&self.format.to_string());
+        hasher.write_str(&self.source);
+        hasher.finish()
+    }
+
+    /// The key this source is rendered under, which also covers the version
+    /// of its renderer and the parts of its deck configuration that affect how
+    /// it renders.
+    pub fn render_hash(&self) -> i64 {
+        let config = &self.config;
+        let mut hasher = ContentHasher::new("render");
+        hasher.write_i64(self.content_hash());
+
+        match self.format {
+            Format::Markdown => {
+                hasher.write_i64(crate::render::MARKDOWN_VERSION);
+                hasher.write_str(crate::render::highlight::VERSION);
+                hasher.write_str(crate::render::highlight::CLASS_PREFIX);
+
+                let options = &config.markdown;
+                for enabled in [
+                    options.footnotes,
+                    options.strikethrough,
+                    options.tasklists,
+                    options.definition_lists,
+                    options.smart_punctuation,
+                    options.heading_attributes,
+                    config.trusted,
+                ] {
+                    hasher.write_i64(enabled.into());
+                }
+            }
+            Format::Tex => {
+                hasher.write_i64(crate::render::SVG_VERSION);
+                hasher.write_str(&config.tex_preamble);
+            }
+            Format::Typst | Format::TypstHtml => {
+                match self.format {
+                    Format::TypstHtml => {
+                        hasher.write_i64(crate::render::TYPST_HTML_VERSION);
+                        hasher.write_i64(config.trusted.into());
+                    }
+                    _ => hasher.write_i64(crate::render::SVG_VERSION),
+                }
+                hasher.write_str(&config.typst_preamble);
+                for dirs in [&config.typst_packages, &config.typst_fonts] {
+                    hasher.write_i64(dirs.len() as i64);
+                    for dir in dirs {
+                        hasher.write_i64(dir.hash);
+                    }
+                }
+            }
+        }
+
+        hasher.finish()
+    }
+}
+
+impl Asset {
+    /// The hash of the type and contents of this asset.
+    pub fn content_hash(&self) -> i64 {
+        let mut hasher = ContentHasher::new("asset");
+        hasher.write_str(&self.mime);
+        hasher.write_bytes(&self.data);
+        hasher.finish()
+    }
+}
+
+/// The hash of the files in the directory at `path`, including those in its
+/// subdirectories, along with their paths relative to it.
+pub fn dir_content_hash(path: &Path) -> std::io::Result<i64> {
+    fn write_dir(hasher: &mut ContentHasher, root: &Path, dir: &Path) -> std::io::Result<()> {
+        let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                write_dir(hasher, root, &path)?;
+                continue;
+            }
+
+            let relative = path.strip_prefix(root).expect("entry to be in root");
+            hasher.write_bytes(relative.as_os_str().as_encoded_bytes());
+            hasher.write_bytes(&std::fs::read(&path)?);
+        }
+
+        Ok(())
+    }
+
+    let mut hasher = ContentHasher::new("dir");
+    write_dir(&mut hasher, path, path)?;
+    Ok(hasher.finish())
+}
+
+impl Topic {
+    /// The hash of the path of this topic.
+    pub fn content_hash(&self) -> i64 {
+        let mut hasher = ContentHasher::new("topic");
+        hasher.write_i64(self.0.len() as i64);
+        for segment in self.0.iter() {
+            hasher.write_str(segment);
+        }
+        hasher.finish()
+    }
+
+    /// The hash of the parent of this topic, if it has one.
+    pub fn parent_content_hash(&self) -> Option<i64> {
+        match self.0.len() {
+            0 | 1 => None,
+            len => Some(Topic(self.0[..len - 1].to_vec()).content_hash()),
+        }
+    }
+}
+
+#[test]
+fn content_hash_is_stable() {
+    let source = Source {
+        source: "$x^2$".to_string(),
+        format: crate::Format::Markdown,
+        config: Default::default(),
+    };
+    assert_eq!(source.content_hash(), 2610384870388128405);
+    assert_ne!(source.render_hash(), source.content_hash());
+
+    let typst = Source {
+        source: "$x^2$".to_string(),
+        format: Format::Typst,
+        config: std::sync::Arc::new(crate::DeckConfig {
+            typst_preamble: "#let x = 1".to_string(),
+            ..Default::default()
+        }),
+    };
+    assert_ne!(typst.render_hash(), typst.content_hash());
+
+    let topic = "chemistry/organic".parse::<Topic>().unwrap();
+    assert_eq!(topic.content_hash(), -19987306750845700);
+    assert_eq!(
+        topic.parent_content_hash(),
+        Some("chemistry".parse::<Topic>().unwrap().content_hash())
+    );
+}
@@ -1,18 +1,28 @@
 use std::collections::HashSet;
 use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
 
+mod cloze;
 mod deserialize;
+pub mod hash;
 pub mod loader;
 pub mod render;
 
-#[derive(Debug, Default, serde::Deserialize, Hash)]
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, Hash,
+)]
 #[serde(rename_all = "lowercase")]
 pub enum Format {
     #[default]
     Markdown,
     Tex,
     Typst,
+    /// Typst exported as HTML rather than as an image, for cards that are
+    /// mostly text.
+    #[serde(rename = "typst-html")]
+    TypstHtml,
 }
 
 impl Display for Format {
@@ -21,6 +31,25 @@ impl Display for Format {
             Self::Markdown => write!(f, "markdown"),
             Self::Tex => write!(f, "tex"),
             Self::Typst => write!(f, "typst"),
+            Self::TypstHtml => write!(f, "typst-html"),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("unknown render format: {0}")]
+pub struct UnknownFormat(pub String);
+
+impl FromStr for Format {
+    type Err = UnknownFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "markdown" => Ok(Self::Markdown),
+            "tex" => Ok(Self::Tex),
+            "typst" => Ok(Self::Typst),
+            "typst-html" => Ok(Self::TypstHtml),
+            _ => Err(UnknownFormat(s.to_string())),
         }
     }
 }
@@ -34,16 +63,108 @@ impl Display for Topic {
     }
 }
 
-#[derive(Debug, Hash)]
+/// Configuration shared by every deck in a directory and its subdirectories.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct DeckConfig {
+    /// Inserted into the preamble of every TeX source, after the default
+    /// packages, from `_preamble.tex` files in the directory and its ancestors.
+    pub tex_preamble: String,
+    /// Inserted before every Typst source, after the default page setup, from
+    /// `_preamble.typ` files in the directory and its ancestors.
+    pub typst_preamble: String,
+    /// Directories that `@local` Typst packages are resolved from, from
+    /// `_packages` directories in the directory and its ancestors, nearest last.
+    pub typst_packages: Vec<DeckDir>,
+    /// Directories of extra fonts for Typst, from `_fonts` directories in the
+    /// directory and its ancestors.
+    pub typst_fonts: Vec<DeckDir>,
+    /// Markdown extensions enabled by `_deck.toml` files in the directory and
+    /// its ancestors.
+    pub markdown: MarkdownOptions,
+    /// Whether to skip sanitizing the HTML rendered from Markdown, so that it
+    /// can contain any HTML, from `_deck.toml` files in the directory and its
+    /// ancestors.
+    pub trusted: bool,
+}
+
+/// Optional Markdown extensions, all of which are off by default.
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize,
+)]
+pub struct MarkdownOptions {
+    /// `[^1]` references to footnotes.
+    pub footnotes: bool,
+    /// `~~struck~~` text.
+    pub strikethrough: bool,
+    /// `- [x]` task list items.
+    pub tasklists: bool,
+    /// Terms followed by `: definition` lines.
+    pub definition_lists: bool,
+    /// Curly quotes, dashes and ellipses from their ASCII forms.
+    pub smart_punctuation: bool,
+    /// `{#id .class}` attributes after headings.
+    pub heading_attributes: bool,
+}
+
+/// A directory of files used when rendering, like fonts or packages.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct DeckDir {
+    pub path: PathBuf,
+    /// The [hash of its contents](hash::dir_content_hash), so that renders
+    /// are invalidated when the files change.
+    pub hash: i64,
+}
+
+#[derive(Debug, Clone, Hash, serde::Serialize, serde::Deserialize)]
 pub struct Source {
     pub source: String,
     pub format: Format,
+    pub config: Arc<DeckConfig>,
 }
 
 #[derive(Debug, Hash)]
 pub struct Rendered {
     pub source: Source,
     pub html: String,
+    /// Files the HTML refers to by their [URL](Asset::url).
+    pub assets: Vec<Asset>,
+}
+
+/// A file referenced by rendered HTML, like an SVG diagram, which is served
+/// on its own so that browsers can cache it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct Asset {
+    pub mime: String,
+    #[serde(with = "base64_data")]
+    pub data: Vec<u8>,
+}
+
+/// Asset data serialized as a base64 string, rather than serde's default of
+/// an array with a number for every byte.
+mod base64_data {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let data = String::deserialize(deserializer)?;
+        STANDARD.decode(data).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CardKind {
+    #[default]
+    Basic,
+    /// The sibling of a basic card with its term and definition swapped.
+    Reversed,
+    /// One of the sibling cards generated from a cloze deletion card, with
+    /// the deletions of the given index blanked out.
+    Cloze(u32),
 }
 
 #[derive(Debug)]
@@ -51,4 +172,5 @@ pub struct Card<T> {
     pub term: T,
     pub definition: T,
     pub topics: HashSet<Arc<Topic>>,
+    pub kind: CardKind,
 }
@@ -1,17 +1,100 @@
 use itertools::Itertools;
 
-use crate::{Card, Source, Topic, deserialize};
+use crate::{Card, DeckConfig, DeckDir, MarkdownOptions, Source, Topic, deserialize, hash};
+use std::fmt::Display;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+/// Every error found in a deck file, along with its contents so they can be
+/// shown with a snippet of the offending source.
 #[derive(Debug, thiserror::Error)]
-#[error("failure to deserialize error")]
-pub struct DeserializeError(#[from] toml::de::Error);
+pub struct DeserializeError {
+    pub path: PathBuf,
+    pub errors: Vec<deserialize::Error>,
+    content: String,
+}
+
+impl Display for DeserializeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, err) in self.errors.iter().enumerate() {
+            if i > 0 {
+                write!(f, "\n\n")?;
+            }
+
+            write!(f, "{}", err.message)?;
+
+            let Some(span) = &err.span else {
+                write!(f, "\n --> {}", self.path.display())?;
+                continue;
+            };
+
+            // spans can cover a whole table, so only their first line is shown
+            let start = span.start.min(self.content.len());
+            let line_start = self.content[..start].rfind('\n').map_or(0, |i| i + 1);
+            let line_end = self.content[start..]
+                .find('\n')
+                .map_or(self.content.len(), |i| i + start);
+
+            let line = &self.content[line_start..line_end];
+            let line_number = self.content[..start].matches('\n').count() + 1;
+            let column = self.content[line_start..start].chars().count() + 1;
+            let width = self.content[start..span.end.clamp(start, line_end)]
+                .chars()
+                .count()
+                .max(1);
+            let gutter = " ".repeat(line_number.to_string().len());
+
+            write!(
+                f,
+                "\n{gutter}--> {}:{line_number}:{column}",
+                self.path.display()
+            )?;
+            write!(f, "\n{gutter} |")?;
+            write!(f, "\n{line_number} | {line}")?;
+            write!(
+                f,
+                "\n{gutter} | {}{}",
+                " ".repeat(column - 1),
+                "^".repeat(width)
+            )?;
+        }
+
+        Ok(())
+    }
+}
 
 #[derive(Debug, thiserror::Error)]
 #[error("failed to decode utf-8")]
 pub struct Utf8Error;
 
+/// An error loading a file or directory, which only affects the decks in it.
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+    #[error("failed to read {}: {err}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        err: std::io::Error,
+    },
+    #[error(transparent)]
+    DeckConfig(#[from] DeckConfigError),
+}
+
+impl LoadError {
+    fn io(path: impl Into<PathBuf>) -> impl FnOnce(std::io::Error) -> Self {
+        let path = path.into();
+        move |err| Self::Io { path, err }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid deck configuration in {}: {err}", path.display())]
+pub struct DeckConfigError {
+    pub path: PathBuf,
+    #[source]
+    pub err: toml::de::Error,
+}
+
 #[derive(Debug, Clone)]
 enum PathSegments {
     Root,
@@ -37,22 +120,168 @@ impl PathSegments {
     }
 }
 
+/// File in a deck directory that is added to the preamble of every TeX
+/// source in the directory and its subdirectories.
+const TEX_PREAMBLE_FILE: &str = "_preamble.tex";
+
+/// File in a deck directory that is added before every Typst source in the
+/// directory and its subdirectories.
+const TYPST_PREAMBLE_FILE: &str = "_preamble.typ";
+
+/// Directory in a deck directory that `@local` Typst packages are resolved
+/// from, laid out as `{name}/{version}`.
+const TYPST_PACKAGES_DIR: &str = "_packages";
+
+/// Directory in a deck directory of extra fonts for Typst.
+const TYPST_FONTS_DIR: &str = "_fonts";
+
+/// File in a deck directory with settings for the directory and its
+/// subdirectories, which override those of its ancestors.
+const DECK_CONFIG_FILE: &str = "_deck.toml";
+
+/// The contents of a [`DECK_CONFIG_FILE`], where unset values are inherited.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeckConfigFile {
+    trusted: Option<bool>,
+    #[serde(default)]
+    markdown: MarkdownConfigFile,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MarkdownConfigFile {
+    footnotes: Option<bool>,
+    strikethrough: Option<bool>,
+    tasklists: Option<bool>,
+    definition_lists: Option<bool>,
+    smart_punctuation: Option<bool>,
+    heading_attributes: Option<bool>,
+}
+
+impl MarkdownConfigFile {
+    fn apply(self, options: &mut MarkdownOptions) {
+        for (value, option) in [
+            (self.footnotes, &mut options.footnotes),
+            (self.strikethrough, &mut options.strikethrough),
+            (self.tasklists, &mut options.tasklists),
+            (self.definition_lists, &mut options.definition_lists),
+            (self.smart_punctuation, &mut options.smart_punctuation),
+            (self.heading_attributes, &mut options.heading_attributes),
+        ] {
+            if let Some(value) = value {
+                *option = value;
+            }
+        }
+    }
+}
+
+fn read_deck_config_file(path: PathBuf) -> Result<Option<DeckConfigFile>, LoadError> {
+    let Some(content) = read_optional(&path)? else {
+        return Ok(None);
+    };
+
+    toml::from_str(&content)
+        .map(Some)
+        .map_err(|err| DeckConfigError { path, err }.into())
+}
+
+fn read_optional(path: &Path) -> Result<Option<String>, LoadError> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(LoadError::io(path)(err)),
+    }
+}
+
+fn deck_dir(path: PathBuf) -> Result<Option<DeckDir>, LoadError> {
+    if !path.is_dir() {
+        return Ok(None);
+    }
+
+    let hash = hash::dir_content_hash(&path).map_err(LoadError::io(&path))?;
+    Ok(Some(DeckDir { path, hash }))
+}
+
+fn append_preamble(preamble: &mut String, addition: &str) {
+    if !preamble.is_empty() {
+        preamble.push('\n');
+    }
+    preamble.push_str(addition);
+}
+
+/// The configuration of the deck directory at `path`, which extends that of its parent.
+fn dir_config(path: &Path, parent: &Arc<DeckConfig>) -> Result<Arc<DeckConfig>, LoadError> {
+    let tex_preamble = read_optional(&path.join(TEX_PREAMBLE_FILE))?;
+    let typst_preamble = read_optional(&path.join(TYPST_PREAMBLE_FILE))?;
+    let typst_packages = deck_dir(path.join(TYPST_PACKAGES_DIR))?;
+    let typst_fonts = deck_dir(path.join(TYPST_FONTS_DIR))?;
+    let deck_config_file = read_deck_config_file(path.join(DECK_CONFIG_FILE))?;
+
+    if tex_preamble.is_none()
+        && typst_preamble.is_none()
+        && typst_packages.is_none()
+        && typst_fonts.is_none()
+        && deck_config_file.is_none()
+    {
+        return Ok(Arc::clone(parent));
+    }
+
+    let mut config = DeckConfig::clone(parent);
+    if let Some(preamble) = tex_preamble {
+        append_preamble(&mut config.tex_preamble, &preamble);
+    }
+    if let Some(preamble) = typst_preamble {
+        append_preamble(&mut config.typst_preamble, &preamble);
+    }
+    config.typst_packages.extend(typst_packages);
+    config.typst_fonts.extend(typst_fonts);
+    if let Some(file) = deck_config_file {
+        config.trusted = file.trusted.unwrap_or(config.trusted);
+        file.markdown.apply(&mut config.markdown);
+    }
+
+    Ok(Arc::new(config))
+}
+
 #[derive(Debug)]
 pub struct FileContents {
     pub path: PathBuf,
+    config: Arc<DeckConfig>,
+    namespace: Option<Arc<str>>,
     path_segments: Vec<Arc<str>>,
     content: String,
 }
 
 impl FileContents {
-    pub fn into_cards(self) -> Result<Vec<Card<Source>>, toml::de::Error> {
-        let mut cards = deserialize::parse(&self.content)?;
+    pub fn into_cards(self) -> Result<Vec<Card<Source>>, DeserializeError> {
+        let mut cards = match deserialize::parse(&self.content) {
+            Ok(cards) => cards,
+            Err(errors) => {
+                return Err(DeserializeError {
+                    path: self.path,
+                    errors,
+                    content: self.content,
+                });
+            }
+        };
         let topics = Topic(self.path_segments)
             .ancestors()
             .map(Arc::new)
             .collect_vec();
 
         for card in cards.iter_mut() {
+            card.term.config = Arc::clone(&self.config);
+            card.definition.config = Arc::clone(&self.config);
+
+            if let Some(namespace) = &self.namespace {
+                card.topics = card
+                    .topics
+                    .drain()
+                    .map(|topic| Arc::new(topic.prepend(Arc::clone(namespace))))
+                    .collect();
+            }
+
             card.topics.extend(topics.iter().cloned());
         }
 
@@ -60,34 +289,57 @@ impl FileContents {
     }
 }
 
-pub fn load_dir(path: impl AsRef<Path>) -> impl Iterator<Item = std::io::Result<FileContents>> {
-    type BoxedIter = Box<dyn Iterator<Item = std::io::Result<FileContents>>>;
+/// Load every deck in the directory at `path`.
+///
+/// A file or directory that cannot be read is reported in place of its decks,
+/// and the rest are still loaded.
+///
+/// If `namespace` is given, it becomes the top-level segment of every topic
+/// in the directory, including those declared in the decks themselves.
+pub fn load_dir(
+    path: impl AsRef<Path>,
+    namespace: Option<Arc<str>>,
+) -> impl Iterator<Item = Result<FileContents, LoadError>> {
+    type BoxedIter = Box<dyn Iterator<Item = Result<FileContents, LoadError>>>;
 
-    fn load_dir_inner(path: impl AsRef<Path>, parent: Arc<PathSegments>) -> BoxedIter {
+    fn load_dir_inner(
+        path: impl AsRef<Path>,
+        namespace: Option<Arc<str>>,
+        parent: Arc<PathSegments>,
+        config: &Arc<DeckConfig>,
+    ) -> BoxedIter {
         let entries = match std::fs::read_dir(&path) {
             Ok(entry) => entry,
+            Err(err) => return Box::new(std::iter::once(Err(LoadError::io(path.as_ref())(err)))),
+        };
+
+        let config = match dir_config(path.as_ref(), config) {
+            Ok(config) => config,
             Err(err) => return Box::new(std::iter::once(Err(err))),
         };
 
+        let dir = path.as_ref().to_path_buf();
         let files = entries.flat_map(move |entry| -> BoxedIter {
             let entry = match entry {
                 Ok(entry) => entry,
-                Err(err) => return Box::new(std::iter::once(Err(err))),
+                Err(err) => return Box::new(std::iter::once(Err(LoadError::io(&dir)(err)))),
             };
 
             let file_type = match entry.file_type() {
                 Ok(entry) => entry,
-                Err(err) => return Box::new(std::iter::once(Err(err))),
+                Err(err) => {
+                    return Box::new(std::iter::once(Err(LoadError::io(entry.path())(err))));
+                }
             };
 
             let file_name = entry.file_name();
             let file_name = match file_name.to_str() {
                 Some(file_name) => file_name,
                 None => {
-                    return Box::new(std::iter::once(Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        Utf8Error,
-                    ))));
+                    return Box::new(std::iter::once(Err(LoadError::Io {
+                        path: entry.path(),
+                        err: std::io::Error::new(std::io::ErrorKind::Other, Utf8Error),
+                    })));
                 }
             };
             let segment = Arc::from(
@@ -103,14 +355,14 @@ pub fn load_dir(path: impl AsRef<Path>) -> impl Iterator<Item = std::io::Result<
             });
 
             if file_type.is_dir() {
-                if file_name == ".git" {
+                if matches!(file_name, ".git" | TYPST_PACKAGES_DIR | TYPST_FONTS_DIR) {
                     return Box::new(std::iter::empty());
                 }
 
-                return load_dir_inner(entry.path(), segments);
+                return load_dir_inner(entry.path(), namespace.clone(), segments, &config);
             }
 
-            if !file_name.ends_with(".toml") {
+            if !file_name.ends_with(".toml") || file_name == DECK_CONFIG_FILE {
                 return Box::new(std::iter::empty());
             }
 
@@ -118,11 +370,13 @@ pub fn load_dir(path: impl AsRef<Path>) -> impl Iterator<Item = std::io::Result<
 
             let content = match std::fs::read_to_string(&path) {
                 Ok(entry) => entry,
-                Err(err) => return Box::new(std::iter::once(Err(err))),
+                Err(err) => return Box::new(std::iter::once(Err(LoadError::io(path)(err)))),
             };
 
             Box::new(std::iter::once(Ok(FileContents {
                 path: path,
+                config: Arc::clone(&config),
+                namespace: namespace.clone(),
                 path_segments: segments.into_vec(),
                 content,
             })))
@@ -131,5 +385,145 @@ pub fn load_dir(path: impl AsRef<Path>) -> impl Iterator<Item = std::io::Result<
         Box::new(files)
     }
 
-    load_dir_inner(path, Arc::new(PathSegments::Root))
+    let root = match &namespace {
+        Some(namespace) => PathSegments::Child {
+            parent: Arc::new(PathSegments::Root),
+            segment: Arc::clone(namespace),
+        },
+        None => PathSegments::Root,
+    };
+
+    load_dir_inner(path, namespace, Arc::new(root), &Arc::default())
+}
+
+#[test]
+fn deserialize_error_snippet() {
+    let err = FileContents {
+        path: PathBuf::from("deck.toml"),
+        config: Arc::default(),
+        namespace: None,
+        path_segments: Vec::new(),
+        content: "[[cards]]\nterm = \"a\"\ndefinition = 1\n".to_string(),
+    }
+    .into_cards()
+    .unwrap_err();
+
+    assert_eq!(
+        err.to_string(),
+        "invalid side of card\n --> deck.toml:3:14\n  |\n3 | definition = 1\n  |              ^"
+    );
+}
+
+#[test]
+fn config_is_inherited() {
+    let root = std::env::temp_dir().join(format!("flashcards-config-{}", std::process::id()));
+    let child = root.join("child");
+    std::fs::create_dir_all(&child).unwrap();
+    std::fs::write(root.join(TEX_PREAMBLE_FILE), r"\usepackage{a}").unwrap();
+    std::fs::write(child.join(TEX_PREAMBLE_FILE), r"\usepackage{b}").unwrap();
+    std::fs::write(
+        root.join(DECK_CONFIG_FILE),
+        "trusted = true\n\n[markdown]\nstrikethrough = true\nsmart_punctuation = true\n",
+    )
+    .unwrap();
+    std::fs::write(
+        child.join(DECK_CONFIG_FILE),
+        "[markdown]\nsmart_punctuation = false\n",
+    )
+    .unwrap();
+
+    let root_config = dir_config(&root, &Arc::default()).unwrap();
+    let child_config = dir_config(&child, &root_config).unwrap();
+    std::fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(
+        child_config.tex_preamble,
+        "\\usepackage{a}\n\\usepackage{b}"
+    );
+    assert!(child_config.trusted);
+    assert_eq!(
+        child_config.markdown,
+        MarkdownOptions {
+            strikethrough: true,
+            ..Default::default()
+        }
+    );
+}
+
+#[test]
+fn unreadable_deck_fails_alone() {
+    let root = std::env::temp_dir().join(format!("flashcards-unreadable-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("broken.toml"), b"\xff\xfe").unwrap();
+    std::fs::write(root.join("fine.toml"), "").unwrap();
+
+    let mut loaded = load_dir(&root, None).collect_vec();
+    std::fs::remove_dir_all(&root).unwrap();
+    loaded.sort_by_key(|file| file.is_ok());
+
+    assert!(matches!(
+        &loaded[..],
+        [Err(LoadError::Io { path, .. }), Ok(file)]
+            if path.ends_with("broken.toml") && file.path.ends_with("fine.toml")
+    ));
+}
+
+#[test]
+fn invalid_deck_config_fails_alone() {
+    let root = std::env::temp_dir().join(format!("flashcards-invalid-{}", std::process::id()));
+    let broken = root.join("broken");
+    std::fs::create_dir_all(&broken).unwrap();
+    std::fs::write(broken.join(DECK_CONFIG_FILE), "trusted = \"yes\"\n").unwrap();
+    std::fs::write(broken.join("deck.toml"), "").unwrap();
+    std::fs::write(root.join("fine.toml"), "").unwrap();
+
+    let mut loaded = load_dir(&root, None).collect_vec();
+    std::fs::remove_dir_all(&root).unwrap();
+    loaded.sort_by_key(|file| file.is_ok());
+
+    assert!(matches!(
+        &loaded[..],
+        [Err(LoadError::DeckConfig(err)), Ok(file)]
+            if err.path.ends_with(DECK_CONFIG_FILE) && file.path.ends_with("fine.toml")
+    ));
+}
+
+#[test]
+fn typst_dirs_are_found() {
+    let root = std::env::temp_dir().join(format!("flashcards-typst-dirs-{}", std::process::id()));
+    let child = root.join("child");
+    std::fs::create_dir_all(root.join(TYPST_FONTS_DIR)).unwrap();
+    std::fs::create_dir_all(child.join(TYPST_FONTS_DIR)).unwrap();
+    std::fs::create_dir_all(root.join(TYPST_PACKAGES_DIR).join("package/0.1.0")).unwrap();
+    std::fs::write(root.join(TYPST_FONTS_DIR).join("font.otf"), b"font").unwrap();
+    // neither directory holds decks, even if it has .toml files
+    std::fs::write(
+        root.join(TYPST_PACKAGES_DIR)
+            .join("package/0.1.0/typst.toml"),
+        "[package]\n",
+    )
+    .unwrap();
+
+    let root_config = dir_config(&root, &Arc::default()).unwrap();
+    let child_config = dir_config(&child, &root_config).unwrap();
+    let loaded = load_dir(&root, None).collect_vec();
+    std::fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(
+        child_config
+            .typst_fonts
+            .iter()
+            .map(|dir| dir.path.clone())
+            .collect_vec(),
+        [root.join(TYPST_FONTS_DIR), child.join(TYPST_FONTS_DIR)]
+    );
+    assert_eq!(
+        child_config
+            .typst_packages
+            .iter()
+            .map(|dir| dir.path.clone())
+            .collect_vec(),
+        [root.join(TYPST_PACKAGES_DIR)]
+    );
+    assert!(loaded.is_empty());
 }
@@ -1,217 +1,555 @@
-use crate::{Card, Format, Rendered, Source};
-use base64::Engine;
+use crate::{Asset, Card, DeckConfig, Format, MarkdownOptions, Rendered, Source};
 use pulldown_cmark as md;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 
+pub(crate) mod highlight;
+pub mod pdf;
+mod sanitize;
 mod tex;
+mod theme;
+mod typst_engine;
+pub mod worker;
 
-mod katex_scanner {
-    #[derive(Debug, Clone)]
-    pub struct Scanner<'a> {
-        input: &'a str,
-        state: State,
-        position: usize,
-    }
+pub use tex::TexOptions;
 
-    impl<'a> Scanner<'a> {
-        pub fn new(input: &'a str) -> Self {
-            Self {
-                input,
-                state: State::Text,
-                position: 0,
-            }
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Malformatted TeX: {0}")]
+    TexError(#[from] tex::Error),
+    #[error("Typst error: {0}")]
+    TypstAsLibError(#[from] typst_as_lib::TypstAsLibError),
+    #[error("Typst error: {0:?}")]
+    TypstError(ecow::vec::EcoVec<typst::diag::SourceDiagnostic>),
+    #[error("KaTeX error in `{snippet}`: {err}")]
+    KatexError {
+        snippet: String,
+        #[source]
+        err: katex::Error,
+    },
+    #[error("rendering {} took longer than the time limit of {limit:?}", path.display())]
+    Timeout { path: PathBuf, limit: Duration },
+    /// An error from rendering in a worker process, of which only the message is sent back.
+    #[error("{0}")]
+    Worker(String),
+    #[error("render worker crashed rendering {} ({status})", path.display())]
+    WorkerCrashed {
+        path: PathBuf,
+        status: std::process::ExitStatus,
+    },
+    #[error("failed to run render worker: {0}")]
+    WorkerIo(#[source] std::io::Error),
+    #[error("invalid message to or from render worker: {0}")]
+    WorkerMessage(#[from] serde_json::Error),
+}
+
+/// State shared between every source rendered in a build.
+///
+/// Build one and reuse it, including across threads, so that expensive state
+/// like the Typst engines and their fonts is only set up once, and only once a
+/// source needs it.
+pub struct Renderer {
+    typst: Mutex<HashMap<typst_engine::Environment, Arc<OnceLock<Arc<typst_engine::Engine>>>>>,
+    pdf_converter: Box<dyn pdf::PdfConverter>,
+    tex_options: TexOptions,
+    workers: Option<worker::WorkerPool>,
+}
+
+impl Default for Renderer {
+    fn default() -> Self {
+        Self {
+            typst: Mutex::default(),
+            pdf_converter: Box::new(pdf::PdfToCairo::default()),
+            tex_options: TexOptions::default(),
+            workers: None,
         }
     }
+}
 
-    #[test]
-    fn katex_scanner_works() {
-        let mut scanner = Scanner::new("$a$b$$c$$");
-        assert_eq!(scanner.next().unwrap(), Event::Text(""));
-        assert_eq!(scanner.next().unwrap(), Event::Inline("a"));
-        assert_eq!(scanner.next().unwrap(), Event::Text("b"));
-        assert_eq!(scanner.next().unwrap(), Event::Block("c"));
+impl Renderer {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    const INLINE_DELIMETER: &str = "$";
-    const BLOCK_DELIMETER: &str = "$$";
+    /// Use `converter` to turn the PDFs produced by TeX into SVGs, instead of `pdftocairo`.
+    pub fn with_pdf_converter(mut self, converter: impl pdf::PdfConverter + 'static) -> Self {
+        self.pdf_converter = Box::new(converter);
+        self
+    }
 
-    impl<'a> Iterator for Scanner<'a> {
-        type Item = Event<'a>;
+    pub fn with_tex_options(mut self, options: TexOptions) -> Self {
+        self.tex_options = options;
+        self
+    }
 
-        fn next(&mut self) -> Option<Self::Item> {
-            let input = &self.input[self.position..];
+    /// Render TeX and Typst in worker processes from `workers`, which can
+    /// be killed when they take longer than the pool's time limit or run out
+    /// of memory, and which only fail the source they were rendering when
+    /// they crash.
+    pub fn with_workers(mut self, workers: worker::WorkerPool) -> Self {
+        self.workers = Some(workers);
+        self
+    }
 
-            if input.is_empty() {
-                return None;
-            }
+    /// Render both sides of `card`, loaded from `path`, which errors name.
+    pub fn render_card(&self, card: Card<Source>, path: &Path) -> Result<Card<Rendered>, Error> {
+        Ok(Card {
+            term: self.render(card.term, path)?,
+            definition: self.render(card.definition, path)?,
+            topics: card.topics,
+            kind: card.kind,
+        })
+    }
 
-            match self.state {
-                State::Text => {
-                    let text = match (input.find(INLINE_DELIMETER), input.find(BLOCK_DELIMETER)) {
-                        (None, Some(_)) => {
-                            unimplemented!("block delimeter should imply inline delimeter exists")
-                        }
-                        (Some(inline_start), Some(block_start)) if block_start <= inline_start => {
-                            let content = &input[..block_start];
-                            self.position += block_start + BLOCK_DELIMETER.len();
-                            self.state = State::Block;
-                            content
-                        }
-                        (Some(inline_start), _) => {
-                            let content = &input[..inline_start];
-                            self.position += inline_start + INLINE_DELIMETER.len();
-                            self.state = State::Inline;
-                            content
-                        }
-                        (None, None) => {
-                            self.position = self.input.len();
-                            input
-                        }
-                    };
-                    Some(Event::Text(text))
-                }
-                State::Block => {
-                    let content = if let Some(position) = input.find(BLOCK_DELIMETER) {
-                        let content = &input[..position];
-                        self.position += position + BLOCK_DELIMETER.len();
-                        self.state = State::Text;
-                        Event::Block(content)
-                    } else {
-                        self.position = self.input.len();
-                        Event::Text(input)
-                    };
-                    Some(content)
-                }
-                State::Inline => {
-                    let content = if let Some(position) = input.find(INLINE_DELIMETER) {
-                        let content = &input[..position];
-                        self.position += position + INLINE_DELIMETER.len();
-                        self.state = State::Text;
-                        Event::Inline(content)
-                    } else {
-                        self.position = self.input.len();
-                        Event::Text(input)
-                    };
-                    Some(content)
-                }
+    /// Render `source`, from the card loaded from `path`, which errors name.
+    pub fn render(&self, source: Source, path: &Path) -> Result<Rendered, Error> {
+        let (html, assets) = match &self.workers {
+            Some(workers) if source.format != Format::Markdown => workers.render(&source, path)?,
+            _ => self.html(&source)?,
+        };
+        Ok(Rendered {
+            html,
+            assets,
+            source,
+        })
+    }
+
+    /// Render many sources, each with the path of its card, typesetting the
+    /// TeX among them that shares a preamble in one LaTeX run.
+    ///
+    /// If a batch fails, each of its sources is rendered on its own, so that
+    /// errors are reported for the sources that caused them.
+    pub fn render_many(&self, sources: Vec<(Source, PathBuf)>) -> Vec<Result<Rendered, Error>> {
+        let Some(workers) = &self.workers else {
+            return self.render_batched(sources.into_iter().map(|(source, _)| source).collect());
+        };
+
+        let rendered = workers.render_many(&sources);
+        sources
+            .into_iter()
+            .zip(rendered)
+            .map(|((source, _), rendered)| {
+                let (html, assets) = rendered?;
+                Ok(Rendered {
+                    html,
+                    assets,
+                    source,
+                })
+            })
+            .collect()
+    }
+
+    /// Render many sources in this process, batching TeX like
+    /// [`render_many`](Self::render_many).
+    fn render_batched(&self, sources: Vec<Source>) -> Vec<Result<Rendered, Error>> {
+        let mut tex = HashMap::<&str, Vec<&str>>::new();
+        for source in sources.iter() {
+            if source.format == Format::Tex {
+                tex.entry(&source.config.tex_preamble)
+                    .or_default()
+                    .push(&source.source);
             }
         }
-    }
 
-    #[derive(Debug, Clone, Copy)]
-    pub enum State {
-        Text,
-        Inline,
-        Block,
+        let mut batches = tex
+            .into_iter()
+            .filter(|(_, tex)| tex.len() > 1)
+            .filter_map(|(preamble, tex)| {
+                let rendered = tex::render_batch(
+                    &tex,
+                    preamble,
+                    &self.tex_options,
+                    self.pdf_converter.as_ref(),
+                )
+                .ok()?;
+                Some((preamble.to_string(), rendered.into_iter()))
+            })
+            .collect::<HashMap<_, _>>();
+
+        sources
+            .into_iter()
+            .map(|source| {
+                let batched = match source.format {
+                    Format::Tex => batches
+                        .get_mut(&source.config.tex_preamble)
+                        .and_then(Iterator::next),
+                    Format::Markdown | Format::Typst | Format::TypstHtml => None,
+                };
+
+                // batches have a page for every source, but a source without
+                // one is still rendered on its own rather than lost
+                match batched {
+                    Some(svg) => {
+                        let (html, asset) = svg_image(&svg, &source.source, "tex");
+                        Ok(Rendered {
+                            html,
+                            assets: vec![asset],
+                            source,
+                        })
+                    }
+                    None => {
+                        let (html, assets) = self.html(&source)?;
+                        Ok(Rendered {
+                            html,
+                            assets,
+                            source,
+                        })
+                    }
+                }
+            })
+            .collect()
     }
 
-    #[derive(Debug, PartialEq, Eq)]
-    pub enum Event<'a> {
-        Text(&'a str),
-        Inline(&'a str),
-        Block(&'a str),
+    /// The Typst engine for the fonts and packages of a deck, built the first
+    /// time a source needs it.
+    fn typst_engine(&self, config: &DeckConfig) -> Arc<typst_engine::Engine> {
+        let environment = typst_engine::Environment::new(config);
+        let engine = Arc::clone(
+            self.typst
+                .lock()
+                .unwrap()
+                .entry(environment.clone())
+                .or_default(),
+        );
+
+        // built outside the lock, so that sources of other decks don't wait
+        // for this deck's fonts to load
+        Arc::clone(engine.get_or_init(|| Arc::new(typst_engine::Engine::new(&environment))))
     }
 
-    impl Event<'_> {
-        pub fn render(&self) -> katex::Result<String> {
-            use katex::Opts;
-
-            match self {
-                Self::Text(text) => Ok((*text).to_string()),
-                Self::Block(content) => katex::render_with_opts(
-                    content,
-                    Opts::builder().display_mode(true).build().unwrap(),
-                ),
-                Self::Inline(content) => katex::render(content),
+    fn html(&self, side: &Source) -> Result<(String, Vec<Asset>), Error> {
+        match side.format {
+            Format::Tex => {
+                let svg = tex::render(
+                    &side.source,
+                    &side.config.tex_preamble,
+                    &self.tex_options,
+                    self.pdf_converter.as_ref(),
+                )?;
+                let (html, asset) = svg_image(&svg, &side.source, "tex");
+                Ok((html, vec![asset]))
+            }
+            Format::Markdown => {
+                let html = markdown(&side.source, &side.config.markdown)?;
+                let html = match side.config.trusted {
+                    true => format!("<div>{html}</div>"),
+                    false => format!("<div>{}</div>", sanitize::sanitize(&html)),
+                };
+                Ok((html, Vec::new()))
+            }
+            Format::Typst => {
+                let doc = self.typst_engine(&side.config).compile(side)?;
+                let svg = typst_svg::svg_merged(&doc, typst::layout::Abs::zero());
+                let (html, asset) = svg_image(svg.as_bytes(), &side.source, "typst");
+                Ok((html, vec![asset]))
+            }
+            Format::TypstHtml => {
+                let doc = self
+                    .typst_engine(&side.config)
+                    .compile::<typst::html::HtmlDocument>(side)?;
+                let html = typst_html::html(&doc).map_err(Error::TypstError)?;
+                let html = theme::inline_svgs_current_color(html_body(&html));
+                let html = match side.config.trusted {
+                    true => format!(r#"<div class="typst-html">{html}</div>"#),
+                    false => format!(
+                        r#"<div class="typst-html">{}</div>"#,
+                        sanitize::sanitize(&html)
+                    ),
+                };
+                Ok((html, Vec::new()))
             }
         }
     }
 }
 
-#[derive(thiserror::Error, Debug)]
-pub enum Error {
-    #[error("Malformatted TeX: {0}")]
-    TexError(#[from] tex::Error),
-    #[error("Typst error: {0}")]
-    TypstAsLibError(#[from] typst_as_lib::TypstAsLibError),
-    #[error("Typst error: {0:?}")]
-    TypstError(ecow::vec::EcoVec<typst::diag::SourceDiagnostic>),
-    #[error("KaTeX error: {0}")]
-    KatexError(#[from] katex::Error),
-}
-
-impl TryFrom<Card<Source>> for Card<Rendered> {
-    type Error = Error;
-
-    fn try_from(card: Card<Source>) -> Result<Self, Self::Error> {
-        Ok(Self {
-            term: Rendered {
-                html: render(&card.term)?,
-                source: card.term,
-            },
-            definition: Rendered {
-                html: render(&card.definition)?,
-                source: card.definition,
-            },
-            topics: card.topics,
-        })
+/// Version of how TeX and Typst are rendered to SVG and embedded in HTML,
+/// part of the render hash of their sources so that changes to it re-render
+/// them.
+pub(crate) const SVG_VERSION: i64 = 3;
+
+/// Version of how Typst is exported to HTML, part of the render hash of
+/// `typst-html` sources.
+pub(crate) const TYPST_HTML_VERSION: i64 = 2;
+
+/// Path that assets are served under, followed by their hash.
+pub const ASSET_PATH: &str = "/asset/";
+
+impl Asset {
+    /// Where the server serves this asset.
+    pub fn url(&self) -> String {
+        format!("{ASSET_PATH}{}", self.content_hash())
     }
 }
 
-impl TryFrom<Source> for Rendered {
-    type Error = Error;
+/// An image of `svg`, rendered from `source`, which refers to the SVG as an
+/// asset drawn in the colour of the reader's theme.
+fn svg_image(svg: &[u8], source: &str, class: &str) -> (String, Asset) {
+    let asset = Asset {
+        mime: "image/svg+xml".to_string(),
+        data: theme::themed(&String::from_utf8_lossy(svg)).into_bytes(),
+    };
 
-    fn try_from(source: Source) -> Result<Self, Self::Error> {
-        Ok(Rendered {
-            html: render(&source)?,
-            source,
-        })
-    }
+    let mut escaped_source = String::new();
+    pulldown_cmark_escape::escape_html(&mut escaped_source, source).unwrap();
+
+    let html = format!(
+        r#"<img src="{}" alt="{escaped_source}" title="{escaped_source}" class="w-full h-full {class}">"#,
+        asset.url()
+    );
+    (html, asset)
+}
+
+/// The contents of the `<body>` of a document exported by Typst, which is
+/// embedded in a card rather than shown as a page of its own.
+fn html_body(html: &str) -> &str {
+    let start = html
+        .find("<body>")
+        .map_or(0, |start| start + "<body>".len());
+    let end = html[start..]
+        .rfind("</body>")
+        .map_or(html.len(), |end| start + end);
+    html[start..end].trim()
 }
 
-fn render(side: &Source) -> Result<String, Error> {
+/// A plain-text projection of a source, for full-text search.
+///
+/// Markdown is reduced to its text content, while TeX and Typst fall back to
+/// the raw source.
+pub fn plain_text(side: &Source) -> String {
     match side.format {
-        Format::Tex => Ok(tex::render(&side.source)?),
-        Format::Markdown => Ok(format!("<div>{}</div>", markdown(&side.source)?)),
-        Format::Typst => {
-            let font_options = typst_as_lib::typst_kit_options::TypstKitFontOptions::new()
-                .include_embedded_fonts(true);
-
-            let engine = typst_as_lib::TypstEngine::builder()
-                .main_file(format!(
-                    r##"#set page(width: auto, height: auto, margin: 1em)
-{}"##,
-                    side.source
-                ))
-                .search_fonts_with(font_options)
-                .build();
-
-            let doc = engine.compile::<typst::layout::PagedDocument>().output?;
-            let data = typst_svg::svg_merged(&doc, typst::layout::Abs::zero());
-
-            let engine = base64::engine::GeneralPurpose::new(
-                &base64::alphabet::STANDARD,
-                base64::engine::GeneralPurposeConfig::new(),
-            );
-            let data = engine.encode(data);
-
-            let mut escaped_source = String::new();
-            pulldown_cmark_escape::escape_html(&mut escaped_source, &side.source).unwrap();
-
-            Ok(format!(
-                r#"<img src="data:image/svg+xml;base64,{data}" alt="{escaped_source}" title="{escaped_source}" class="w-full h-full typst">"#
-            ))
+        Format::Tex | Format::Typst | Format::TypstHtml => side.source.clone(),
+        Format::Markdown => {
+            let mut text = String::new();
+            for event in md::Parser::new_ext(&side.source, parser_options(&side.config.markdown)) {
+                match event {
+                    md::Event::Text(content)
+                    | md::Event::Code(content)
+                    | md::Event::InlineMath(content)
+                    | md::Event::DisplayMath(content) => text.push_str(&content),
+                    md::Event::SoftBreak
+                    | md::Event::HardBreak
+                    | md::Event::Rule
+                    | md::Event::End(
+                        md::TagEnd::Paragraph
+                        | md::TagEnd::Heading(_)
+                        | md::TagEnd::Item
+                        | md::TagEnd::TableCell
+                        | md::TagEnd::CodeBlock,
+                    ) => text.push(' '),
+                    _ => {}
+                }
+            }
+            text.trim_end().to_string()
         }
     }
 }
 
-pub fn markdown(text: &str) -> katex::Result<String> {
-    let scanner = katex_scanner::Scanner::new(text);
-    let text = scanner
-        .map(|event| event.render())
-        .collect::<katex::Result<String>>()?;
-    let parser = md::Parser::new_ext(&text, md::Options::ENABLE_TABLES);
+#[test]
+fn plain_text_strips_markdown() {
+    let source = Source {
+        source: "# Title\n\nSome *emphasised* `code`\n\n- one\n- two".to_string(),
+        format: Format::Markdown,
+        config: Default::default(),
+    };
+    assert_eq!(plain_text(&source), "Title Some emphasised code one two");
+}
+
+/// Version of the HTML produced by [`markdown`], part of the render hash of
+/// Markdown sources so that changes to it re-render them.
+pub(crate) const MARKDOWN_VERSION: i64 = 3;
+
+fn parser_options(options: &MarkdownOptions) -> md::Options {
+    let mut parser_options = md::Options::ENABLE_TABLES | md::Options::ENABLE_MATH;
+    for (enabled, option) in [
+        (options.footnotes, md::Options::ENABLE_FOOTNOTES),
+        (options.strikethrough, md::Options::ENABLE_STRIKETHROUGH),
+        (options.tasklists, md::Options::ENABLE_TASKLISTS),
+        (
+            options.definition_lists,
+            md::Options::ENABLE_DEFINITION_LIST,
+        ),
+        (
+            options.smart_punctuation,
+            md::Options::ENABLE_SMART_PUNCTUATION,
+        ),
+        (
+            options.heading_attributes,
+            md::Options::ENABLE_HEADING_ATTRIBUTES,
+        ),
+    ] {
+        parser_options.set(option, enabled);
+    }
+    parser_options
+}
+
+/// Byte ranges of the `$inline$` and `$$display$$` math in Markdown `text`,
+/// including their dollars, as [`markdown`] finds them.
+pub(crate) fn markdown_math(text: &str, options: &MarkdownOptions) -> Vec<Range<usize>> {
+    md::Parser::new_ext(text, parser_options(options))
+        .into_offset_iter()
+        .filter(|(event, _)| matches!(event, md::Event::InlineMath(_) | md::Event::DisplayMath(_)))
+        .map(|(_, range)| range)
+        .collect()
+}
+
+/// Render Markdown to HTML, with `$inline$` and `$$display$$` math rendered by KaTeX.
+///
+/// Math is only recognized in text, so dollars in code are left alone, and a
+/// literal dollar can be written as `\$`.
+pub fn markdown(text: &str, options: &MarkdownOptions) -> Result<String, Error> {
+    let katex = |snippet: &str, display_mode: bool| {
+        let opts = katex::Opts::builder()
+            .display_mode(display_mode)
+            .build()
+            .unwrap();
+        katex::render_with_opts(snippet, opts).map_err(|err| Error::KatexError {
+            snippet: snippet.to_string(),
+            err,
+        })
+    };
+
+    let mut events = Vec::new();
+    // the language and text of the fenced code block being read, if any
+    let mut code_block = None::<(md::CowStr, String)>;
+
+    for event in md::Parser::new_ext(text, parser_options(options)) {
+        match (event, &mut code_block) {
+            (md::Event::Start(md::Tag::CodeBlock(md::CodeBlockKind::Fenced(language))), None)
+                if !language.is_empty() =>
+            {
+                code_block = Some((language, String::new()));
+            }
+            (md::Event::Text(text), Some((_, code))) => code.push_str(&text),
+            (md::Event::End(md::TagEnd::CodeBlock), Some(_)) => {
+                let (language, code) = code_block.take().expect("to be in a code block");
+                match highlight::highlight(&code, &language) {
+                    Some(html) => {
+                        let mut class = String::new();
+                        pulldown_cmark_escape::escape_html(&mut class, &language).unwrap();
+                        events.push(md::Event::Html(
+                            format!(
+                                r#"<pre><code class="language-{class}">{html}</code></pre>
+"#
+                            )
+                            .into(),
+                        ));
+                    }
+                    None => events.extend([
+                        md::Event::Start(md::Tag::CodeBlock(md::CodeBlockKind::Fenced(language))),
+                        md::Event::Text(code.into()),
+                        md::Event::End(md::TagEnd::CodeBlock),
+                    ]),
+                }
+            }
+            (md::Event::InlineMath(snippet), _) => {
+                events.push(md::Event::InlineHtml(katex(&snippet, false)?.into()));
+            }
+            (md::Event::DisplayMath(snippet), _) => {
+                events.push(md::Event::InlineHtml(katex(&snippet, true)?.into()));
+            }
+            (event, _) => events.push(event),
+        }
+    }
 
     let mut result = String::new();
-    md::html::push_html(&mut result, parser);
+    md::html::push_html(&mut result, events.into_iter());
     Ok(result)
 }
+
+#[test]
+fn markdown_math_is_only_in_text() {
+    let html = markdown(
+        r"`$HOME` costs \$5 or $10, but $x^2$ is math",
+        &MarkdownOptions::default(),
+    )
+    .unwrap();
+    assert!(html.contains("<code>$HOME</code>"));
+    assert!(html.contains("costs $5 or $10, but "));
+    assert_eq!(html.matches(r#"class="katex""#).count(), 1);
+
+    let err = markdown(r"$\frac{1}$", &MarkdownOptions::default()).unwrap_err();
+    assert!(err.to_string().contains(r"`\frac{1}`"));
+}
+
+#[test]
+fn markdown_highlights_fenced_code() {
+    let html = markdown(
+        "```python\nprint('$5')\n```\n\n```\nplain\n```",
+        &MarkdownOptions::default(),
+    )
+    .unwrap();
+    assert!(
+        html.contains(r#"<pre><code class="language-python"><span class="hl-source hl-python">"#)
+    );
+    assert!(html.contains("$5"));
+    assert!(html.contains("<pre><code>plain\n</code></pre>"));
+}
+
+#[test]
+fn markdown_extensions_are_opt_in() {
+    let text = "~~wrong~~ \"right\"";
+    assert_eq!(
+        markdown(text, &MarkdownOptions::default()).unwrap(),
+        "<p>~~wrong~~ \"right\"</p>\n"
+    );
+
+    let options = MarkdownOptions {
+        strikethrough: true,
+        smart_punctuation: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        markdown(text, &options).unwrap(),
+        "<p><del>wrong</del> \u{201c}right\u{201d}</p>\n"
+    );
+}
+
+#[test]
+fn typst_html_is_rendered() {
+    let source = Source {
+        source: "Hello #html.elem(\"script\")[alert(1)] $x^2$".to_string(),
+        format: Format::TypstHtml,
+        config: Arc::default(),
+    };
+
+    let rendered = Renderer::new()
+        .render(source, Path::new("card.toml"))
+        .unwrap();
+    assert!(rendered.html.starts_with(r#"<div class="typst-html">"#));
+    assert!(rendered.html.contains("Hello"));
+    // math is laid out as an inline SVG frame
+    assert!(rendered.html.contains("<svg"));
+    assert!(!rendered.html.contains("<script"));
+    assert!(rendered.assets.is_empty());
+}
+
+#[test]
+fn typst_engines_are_shared_per_environment() {
+    let renderer = Renderer::new();
+    let preamble = DeckConfig {
+        typst_preamble: "#set text(red)".to_string(),
+        ..Default::default()
+    };
+    let packages = DeckConfig {
+        typst_packages: vec![crate::DeckDir {
+            path: PathBuf::from("_packages"),
+            hash: 0,
+        }],
+        ..Default::default()
+    };
+
+    let engine = renderer.typst_engine(&DeckConfig::default());
+    // a preamble is part of each source rather than the engine
+    assert!(Arc::ptr_eq(&engine, &renderer.typst_engine(&preamble)));
+    assert!(!Arc::ptr_eq(&engine, &renderer.typst_engine(&packages)));
+}
+
+#[test]
+fn html_body_is_extracted() {
+    let html = "<!DOCTYPE html>\n<html>\n  <head>\n    <meta charset=\"utf-8\">\n  </head>\n  <body>\n    <p>Hello</p>\n  </body>\n</html>\n";
+    assert_eq!(html_body(html), "<p>Hello</p>");
+    assert_eq!(html_body("<p>Hello</p>"), "<p>Hello</p>");
+}
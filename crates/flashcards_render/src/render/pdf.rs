@@ -0,0 +1,273 @@
+//! Conversion of the PDFs produced by TeX into SVGs.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(
+        "could not find `{program}`, which is needed to convert TeX output to SVG (it is part of poppler-utils)"
+    )]
+    NotFound { program: PathBuf },
+    #[error("failed to run `{program}`: {err}")]
+    Io {
+        program: PathBuf,
+        #[source]
+        err: std::io::Error,
+    },
+    #[error("`{program}` failed ({status}): {stderr}")]
+    Failed {
+        program: PathBuf,
+        status: ExitStatus,
+        stderr: String,
+    },
+    #[error("the PDF has no page {page}")]
+    MissingPage { page: usize },
+}
+
+/// The size of a PDF page, in PostScript points.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageSize {
+    pub width: f64,
+    pub height: f64,
+}
+
+/// Something that can convert the pages of a PDF to SVG.
+pub trait PdfConverter: Send + Sync {
+    /// Convert a page, counting from 1, to SVG, or return `None` if the PDF has no such page.
+    fn page_to_svg(&self, pdf: &[u8], page: usize) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Convert every page of a PDF, whose pages have `sizes`, to SVG.
+    ///
+    /// By default, each page is converted on its own.
+    fn pages_to_svg(&self, pdf: &[u8], sizes: &[PageSize]) -> Result<Vec<Vec<u8>>, Error> {
+        each_page_to_svg(self, pdf, sizes.len())
+    }
+}
+
+fn each_page_to_svg(
+    converter: &(impl PdfConverter + ?Sized),
+    pdf: &[u8],
+    pages: usize,
+) -> Result<Vec<Vec<u8>>, Error> {
+    (1..=pages)
+        .map(|page| {
+            converter
+                .page_to_svg(pdf, page)?
+                .ok_or(Error::MissingPage { page })
+        })
+        .collect()
+}
+
+/// Converts PDFs with poppler's `pdftocairo`, run as a subprocess.
+#[derive(Debug, Clone)]
+pub struct PdfToCairo {
+    pub program: PathBuf,
+}
+
+impl Default for PdfToCairo {
+    fn default() -> Self {
+        Self {
+            program: PathBuf::from("pdftocairo"),
+        }
+    }
+}
+
+/// Exit code of `pdftocairo` when the requested pages are out of range.
+const PDFTOCAIRO_PAGE_RANGE_EXIT_CODE: i32 = 99;
+
+impl PdfToCairo {
+    /// Run `pdftocairo` on `pdf` with `args`, returning its output, or `None`
+    /// if the requested pages are out of range.
+    fn run(&self, pdf: &[u8], args: &[&str]) -> Result<Option<Vec<u8>>, Error> {
+        let io_error = |err: std::io::Error| match err.kind() {
+            std::io::ErrorKind::NotFound => Error::NotFound {
+                program: self.program.clone(),
+            },
+            _ => Error::Io {
+                program: self.program.clone(),
+                err,
+            },
+        };
+
+        let mut process = std::process::Command::new(&self.program)
+            .args(["-", "-", "-svg"])
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(io_error)?;
+
+        let mut stdin = process.stdin.take().expect("child to have stdin");
+        stdin.write_all(pdf).map_err(io_error)?;
+        stdin.flush().map_err(io_error)?;
+        drop(stdin);
+
+        let output = process.wait_with_output().map_err(io_error)?;
+        match output.status.code() {
+            Some(0) => Ok(Some(output.stdout)),
+            Some(PDFTOCAIRO_PAGE_RANGE_EXIT_CODE) => Ok(None),
+            _ => Err(Error::Failed {
+                program: self.program.clone(),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            }),
+        }
+    }
+}
+
+impl PdfConverter for PdfToCairo {
+    fn page_to_svg(&self, pdf: &[u8], page: usize) -> Result<Option<Vec<u8>>, Error> {
+        let page = page.to_string();
+        self.run(pdf, &["-f", &page, "-l", &page])
+    }
+
+    /// Convert every page with one run of `pdftocairo`, which writes them as
+    /// the pages of a single SVG, all the size of the largest page.
+    fn pages_to_svg(&self, pdf: &[u8], sizes: &[PageSize]) -> Result<Vec<Vec<u8>>, Error> {
+        if sizes.len() < 2 {
+            return each_page_to_svg(self, pdf, sizes.len());
+        }
+
+        let paper = PageSize {
+            width: sizes
+                .iter()
+                .map(|size| size.width)
+                .fold(0.0, f64::max)
+                .ceil(),
+            height: sizes
+                .iter()
+                .map(|size| size.height)
+                .fold(0.0, f64::max)
+                .ceil(),
+        };
+        let (width, height) = (paper.width.to_string(), paper.height.to_string());
+        let svg = self
+            .run(
+                pdf,
+                &[
+                    "-paperw",
+                    &width,
+                    "-paperh",
+                    &height,
+                    "-nocenter",
+                    "-noshrink",
+                ],
+            )?
+            .ok_or(Error::MissingPage { page: 1 })?;
+
+        // converting the pages one by one still works if cairo lays them out differently
+        match split_pages(&String::from_utf8_lossy(&svg), paper, sizes) {
+            Some(pages) => Ok(pages),
+            None => each_page_to_svg(self, pdf, sizes.len()),
+        }
+    }
+}
+
+/// Split an SVG written by `pdftocairo` with a `<page>` for each of `sizes`,
+/// on paper of size `paper`, into an SVG for each page, or return `None` if
+/// it has a different number of pages.
+///
+/// Each page is drawn in the lower left corner of the paper, so its SVG
+/// shows only that part, along with the definitions shared by every page.
+fn split_pages(svg: &str, paper: PageSize, sizes: &[PageSize]) -> Option<Vec<Vec<u8>>> {
+    let root_start = svg.find("<svg")?;
+    let root_end = root_start + svg[root_start..].find('>')? + 1;
+    let (defs, page_set) = svg[root_end..].split_once("<pageSet>")?;
+    let (page_set, _) = page_set.rsplit_once("</pageSet>")?;
+
+    let pages = page_set
+        .split("<page>")
+        .skip(1)
+        .map(|page| Some(page.rsplit_once("</page>")?.0))
+        .collect::<Option<Vec<_>>>()?;
+    if pages.len() != sizes.len() {
+        return None;
+    }
+
+    pages
+        .into_iter()
+        .zip(sizes)
+        .map(|(page, size)| {
+            let root = &svg[root_start..root_end];
+            let root = with_attribute(root, "width", &format!("{}pt", size.width))?;
+            let root = with_attribute(&root, "height", &format!("{}pt", size.height))?;
+            let view_box = format!(
+                "0 {} {} {}",
+                paper.height - size.height,
+                size.width,
+                size.height
+            );
+            let root = with_attribute(&root, "viewBox", &view_box)?;
+
+            Some(format!("{}{root}{defs}{page}</svg>\n", &svg[..root_start]).into_bytes())
+        })
+        .collect()
+}
+
+/// `tag` with the value of its attribute `name` replaced with `value`.
+fn with_attribute(tag: &str, name: &str, value: &str) -> Option<String> {
+    let start = tag.find(&format!(" {name}=\""))? + name.len() + 3;
+    let end = start + tag[start..].find('"')?;
+    Some(format!("{}{value}{}", &tag[..start], &tag[end..]))
+}
+
+#[test]
+fn missing_converter() {
+    let converter = PdfToCairo {
+        program: PathBuf::from("/nonexistent/pdftocairo"),
+    };
+
+    assert!(matches!(
+        converter.page_to_svg(b"%PDF-1.5", 1),
+        Err(Error::NotFound { .. })
+    ));
+}
+
+#[test]
+fn pages_are_split() {
+    let svg = r##"<?xml version="1.0" encoding="UTF-8"?>
+<svg xmlns="http://www.w3.org/2000/svg" width="30pt" height="20pt" viewBox="0 0 30 20" version="1.2">
+<defs><g id="glyph-0-0"></g></defs>
+<pageSet>
+<page>
+<g id="surface1"><use xlink:href="#glyph-0-0"/></g>
+</page>
+<page>
+<g id="surface2"></g>
+</page>
+</pageSet>
+</svg>
+"##;
+
+    let pages = split_pages(
+        svg,
+        PageSize {
+            width: 30.0,
+            height: 20.0,
+        },
+        &[
+            PageSize {
+                width: 30.0,
+                height: 10.5,
+            },
+            PageSize {
+                width: 12.0,
+                height: 20.0,
+            },
+        ],
+    )
+    .unwrap();
+
+    let first = String::from_utf8(pages[0].clone()).unwrap();
+    assert!(first.contains(r#"width="30pt" height="10.5pt" viewBox="0 9.5 30 10.5""#));
+    assert!(first.contains(r#"<defs><g id="glyph-0-0"></g></defs>"#));
+    assert!(first.contains("surface1") && !first.contains("surface2"));
+    assert!(first.trim_end().ends_with("</svg>"));
+
+    let second = String::from_utf8(pages[1].clone()).unwrap();
+    assert!(second.contains(r#"width="12pt" height="20pt" viewBox="0 0 12 20""#));
+    assert!(second.contains("surface2") && !second.contains("surface1"));
+}
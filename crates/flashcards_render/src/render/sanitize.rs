@@ -0,0 +1,453 @@
+//! Allow-list sanitizing of the HTML rendered from Markdown and Typst.
+//!
+//! Markdown passes raw HTML through, Typst can produce any element with
+//! `html.elem`, and decks are shared between people, so anything that could
+//! run script or escape the card is removed unless the deck is
+//! [trusted](crate::DeckConfig::trusted). What remains is ordinary
+//! formatting, along with the markup produced by KaTeX, the syntax
+//! highlighter and Typst's inline SVGs, and images embedded as data URLs.
+//! Classes are limited to those of that markup, so that a card can't use the
+//! site's own.
+
+use std::borrow::Cow;
+use std::sync::LazyLock;
+
+/// MathML elements produced by KaTeX.
+const MATHML_TAGS: &[&str] = &[
+    "math",
+    "annotation",
+    "semantics",
+    "menclose",
+    "mfrac",
+    "mglyph",
+    "mi",
+    "mlabeledtr",
+    "mn",
+    "mo",
+    "mover",
+    "mpadded",
+    "mphantom",
+    "mroot",
+    "mrow",
+    "mspace",
+    "msqrt",
+    "mstyle",
+    "msub",
+    "msubsup",
+    "msup",
+    "mtable",
+    "mtd",
+    "mtext",
+    "mtr",
+    "munder",
+    "munderover",
+];
+
+/// Presentational attributes of KaTeX's MathML elements.
+const MATHML_ATTRIBUTES: &[&str] = &[
+    "accent",
+    "accentunder",
+    "columnalign",
+    "columnlines",
+    "columnspacing",
+    "depth",
+    "display",
+    "displaystyle",
+    "encoding",
+    "fence",
+    "height",
+    "largeop",
+    "linethickness",
+    "lspace",
+    "mathbackground",
+    "mathcolor",
+    "mathvariant",
+    "maxsize",
+    "minsize",
+    "movablelimits",
+    "notation",
+    "rowlines",
+    "rowspacing",
+    "rspace",
+    "scriptlevel",
+    "separator",
+    "stretchy",
+    "symmetric",
+    "voffset",
+    "width",
+    "xmlns",
+];
+
+/// SVG elements and their attributes, used by KaTeX for stretchy symbols
+/// and by Typst for the math and figures in its HTML export.
+const SVG_TAG_ATTRIBUTES: &[(&str, &[&str])] = &[
+    (
+        "svg",
+        &[
+            "xmlns",
+            "width",
+            "height",
+            "viewBox",
+            "preserveAspectRatio",
+            "style",
+        ],
+    ),
+    ("g", &["transform", "clip-path", "fill", "fill-rule"]),
+    ("defs", &["id"]),
+    ("symbol", &["id", "overflow"]),
+    ("use", &["href", "x", "y", "fill", "fill-rule"]),
+    ("clipPath", &["id"]),
+    (
+        "path",
+        &[
+            "d",
+            "transform",
+            "fill",
+            "fill-rule",
+            "stroke",
+            "stroke-width",
+            "stroke-linecap",
+            "stroke-linejoin",
+            "stroke-miterlimit",
+            "stroke-dasharray",
+        ],
+    ),
+    ("line", &["x1", "x2", "y1", "y2", "stroke-width"]),
+];
+
+/// Classes of the HTML produced by KaTeX, besides those starting with
+/// `katex`, which its stylesheet only styles inside a `.katex` element.
+const KATEX_CLASSES: &[&str] = &[
+    "accent",
+    "accent-body",
+    "accent-full",
+    "accentunder",
+    "allowbreak",
+    "amsrm",
+    "angl",
+    "anglpad",
+    "arraycolsep",
+    "base",
+    "boldsymbol",
+    "boxpad",
+    "brace-center",
+    "brace-left",
+    "brace-right",
+    "cancel-lap",
+    "cancel-pad",
+    "clap",
+    "col-align-c",
+    "col-align-l",
+    "col-align-r",
+    "colorbox",
+    "delim-size4",
+    "delimcenter",
+    "delimsizing",
+    "delimsizinginner",
+    "eqn-num",
+    "fbox",
+    "fcolorbox",
+    "fix",
+    "frac-line",
+    "halfarrow-left",
+    "halfarrow-right",
+    "hdashline",
+    "hide-tail",
+    "hline",
+    "inner",
+    "large-op",
+    "llap",
+    "mathbb",
+    "mathbf",
+    "mathcal",
+    "mathfrak",
+    "mathit",
+    "mathnormal",
+    "mathrm",
+    "mathscr",
+    "mathsf",
+    "mathtt",
+    "mbin",
+    "mclose",
+    "mfrac",
+    "minner",
+    "mop",
+    "mopen",
+    "mord",
+    "mover",
+    "mpunct",
+    "mrel",
+    "mspace",
+    "msupsub",
+    "mtable",
+    "mtight",
+    "mult",
+    "munder",
+    "newline",
+    "nobreak",
+    "nulldelimiter",
+    "op-limits",
+    "op-symbol",
+    "overlay",
+    "overline",
+    "overline-line",
+    "pstrut",
+    "reset-size1",
+    "reset-size10",
+    "reset-size11",
+    "reset-size2",
+    "reset-size3",
+    "reset-size4",
+    "reset-size5",
+    "reset-size6",
+    "reset-size7",
+    "reset-size8",
+    "reset-size9",
+    "rlap",
+    "root",
+    "rule",
+    "size1",
+    "size10",
+    "size11",
+    "size2",
+    "size3",
+    "size4",
+    "size5",
+    "size6",
+    "size7",
+    "size8",
+    "size9",
+    "sizing",
+    "small-op",
+    "sout",
+    "sqrt",
+    "stretchy",
+    "strut",
+    "svg-align",
+    "tag",
+    "text",
+    "textbf",
+    "textit",
+    "textmd",
+    "textrm",
+    "textsf",
+    "texttt",
+    "textup",
+    "thinbox",
+    "underline",
+    "underline-line",
+    "vbox",
+    "vertical-separator",
+    "vlist",
+    "vlist-r",
+    "vlist-s",
+    "vlist-t",
+    "vlist-t2",
+    "x-arrow",
+    "x-arrow-pad",
+];
+
+/// Prefixes of the other classes of generated markup: KaTeX's outermost
+/// elements, highlighted code, Typst's inline SVGs, and pulldown-cmark's code
+/// blocks and footnotes.
+const CLASS_PREFIXES: &[&str] = &[
+    "katex",
+    super::highlight::CLASS_PREFIX,
+    "typst-",
+    "language-",
+    "footnote-",
+];
+
+/// Whether `class` is one that generated markup uses. Any other class could
+/// pick up the site's own styles, and be used to lay a card over the page.
+fn allowed_class(class: &str) -> bool {
+    class == "cloze"
+        || KATEX_CLASSES.contains(&class)
+        || CLASS_PREFIXES
+            .iter()
+            .any(|prefix| class.starts_with(prefix))
+}
+
+/// Elements that the optional Markdown extensions give ids, for footnotes and
+/// heading attributes.
+const ID_TAGS: &[&str] = &["div", "sup", "h1", "h2", "h3", "h4", "h5", "h6"];
+
+/// CSS properties allowed in `style` attributes, which KaTeX uses to size and
+/// position the parts of a formula, Markdown tables use to align cells, and
+/// Typst uses to size its inline SVGs.
+const STYLE_PROPERTIES: &[&str] = &[
+    "background-color",
+    "border-bottom-width",
+    "border-color",
+    "border-right-style",
+    "border-right-width",
+    "border-style",
+    "border-top-width",
+    "border-width",
+    "bottom",
+    "color",
+    "height",
+    "left",
+    "margin",
+    "margin-left",
+    "margin-right",
+    "margin-top",
+    "min-width",
+    "overflow",
+    "padding-left",
+    "text-align",
+    "top",
+    "vertical-align",
+    "width",
+];
+
+static SANITIZER: LazyLock<ammonia::Builder<'static>> = LazyLock::new(|| {
+    let mut builder = ammonia::Builder::default();
+
+    builder
+        .add_tags(MATHML_TAGS)
+        .add_tags(SVG_TAG_ATTRIBUTES.iter().map(|(tag, _)| tag))
+        // task list items, which can only be disabled checkboxes
+        .add_tags(&["input"])
+        .add_tag_attributes("input", &["checked"])
+        .add_tag_attribute_values("input", "type", &["checkbox"])
+        .set_tag_attribute_value("input", "disabled", "")
+        .add_generic_attributes(&["class", "style", "aria-hidden"])
+        .filter_style_properties(STYLE_PROPERTIES.iter().copied().collect())
+        .add_url_schemes(&["data"])
+        .attribute_filter(|element, attribute, value| {
+            if attribute == "class" {
+                let classes = value
+                    .split_ascii_whitespace()
+                    .filter(|class| allowed_class(class))
+                    .collect::<Vec<_>>();
+                return (!classes.is_empty()).then(|| Cow::Owned(classes.join(" ")));
+            }
+
+            // data URLs are only allowed for images, which cannot run script,
+            // and are parsed as browsers would, ignoring case and whitespace
+            let data_url = ammonia::Url::parse(value)
+                .ok()
+                .filter(|url| url.scheme() == "data");
+            if let Some(url) = data_url {
+                let image = url.path().to_ascii_lowercase().starts_with("image/");
+                if !(element == "img" && attribute == "src" && image) {
+                    return None;
+                }
+            }
+            Some(Cow::Borrowed(value))
+        });
+
+    for tag in MATHML_TAGS {
+        builder.add_tag_attributes(tag, MATHML_ATTRIBUTES);
+    }
+    for tag in ID_TAGS {
+        builder.add_tag_attributes(tag, &["id"]);
+    }
+    for (tag, attributes) in SVG_TAG_ATTRIBUTES {
+        builder.add_tag_attributes(tag, *attributes);
+    }
+
+    builder
+});
+
+pub fn sanitize(html: &str) -> String {
+    SANITIZER.clean(html).to_string()
+}
+
+#[test]
+fn sanitize_removes_script() {
+    assert_eq!(
+        sanitize(
+            r#"<p onclick="alert(1)">hi<script>alert(2)</script> <a href="javascript:alert(3)">link</a></p>"#
+        ),
+        r#"<p>hi <a rel="noopener noreferrer">link</a></p>"#
+    );
+    for href in [
+        "data:text/html,<script>alert(1)</script>",
+        "DATA:text/html,<script>alert(1)</script>",
+        " data:text/html,<script>alert(1)</script>",
+    ] {
+        assert_eq!(
+            sanitize(&format!(r#"<a href="{href}">link</a>"#)),
+            r#"<a rel="noopener noreferrer">link</a>"#
+        );
+    }
+    assert_eq!(
+        sanitize(r#"<img src=" DATA:text/html,<script>alert(1)</script>">"#),
+        "<img>"
+    );
+}
+
+#[test]
+fn sanitize_keeps_generated_markup() {
+    let img = r#"<img src="data:image/svg+xml;base64,PHN2Zz4=" alt="x">"#;
+    assert_eq!(sanitize(img), img);
+
+    assert_eq!(
+        sanitize(r#"<input type="text" checked="">"#),
+        r#"<input checked="" disabled="">"#
+    );
+
+    let opts = katex::Opts::builder().display_mode(true).build().unwrap();
+    let html = katex::render_with_opts(r"\sqrt{\frac{x^2}{\color{red}y}}", opts).unwrap();
+    let sanitized = sanitize(&html);
+
+    for expected in [
+        r#"<math xmlns="http://www.w3.org/1998/Math/MathML" display="block">"#,
+        r#"<annotation encoding="application/x-tex">"#,
+        r#"<span class="katex-html" aria-hidden="true">"#,
+        "<svg ",
+        "viewBox=",
+        "<path d=",
+        "color:red",
+    ] {
+        assert!(sanitized.contains(expected), "{expected} in {sanitized}");
+    }
+}
+
+#[test]
+fn sanitize_keeps_typst_frames() {
+    let frame = r##"<svg class="typst-frame" style="overflow: visible; width: 1.5em; height: 1em;" viewBox="0 0 15 10" width="15pt" height="10pt" xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"><g><g transform="translate(0 8)"><use xlink:href="#gA1" x="0" fill="currentColor" fill-rule="nonzero"></use></g></g><defs id="glyph"><symbol id="gA1" overflow="visible"><path d="M 1 0 L 2 1 Z"></path></symbol></defs></svg>"##;
+    let sanitized = sanitize(frame);
+
+    for expected in [
+        r#"<g transform="translate(0 8)">"#,
+        r##"<use xlink:href="#gA1" x="0" fill="currentColor" fill-rule="nonzero">"##,
+        r#"<symbol id="gA1" overflow="visible"><path d="M 1 0 L 2 1 Z">"#,
+        "overflow:visible",
+    ] {
+        assert!(sanitized.contains(expected), "{expected} in {sanitized}");
+    }
+}
+
+#[test]
+fn sanitize_only_keeps_generated_classes() {
+    assert_eq!(
+        sanitize(
+            r#"<div class="fixed inset-0 z-50 cloze katex-display">x</div><span class="hidden">y</span>"#
+        ),
+        r#"<div class="cloze katex-display">x</div><span>y</span>"#
+    );
+    assert_eq!(
+        sanitize(
+            r#"<span class="mord mathnormal hl-keyword typst-frame language-rust footnote-reference">z</span>"#
+        ),
+        r#"<span class="mord mathnormal hl-keyword typst-frame language-rust footnote-reference">z</span>"#
+    );
+
+    // every class KaTeX uses is kept
+    let opts = katex::Opts::builder().display_mode(true).build().unwrap();
+    let html = katex::render_with_opts(
+        r"\begin{pmatrix} \sqrt[3]{x} & \overbrace{a+b}^{n} \\ \cancel{y} & \xrightarrow{f} \end{pmatrix} \tag{1}",
+        opts,
+    )
+    .unwrap();
+    let classes = |html: &str| {
+        html.split(r#"class=""#)
+            .skip(1)
+            .map(|rest| rest[..rest.find('"').unwrap()].to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(classes(&sanitize(&html)), classes(&html));
+}
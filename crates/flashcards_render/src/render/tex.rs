@@ -1,6 +1,5 @@
-use base64::Engine;
-use std::io::Write;
-use std::process::Stdio;
+use super::pdf::{self, PdfConverter};
+use std::path::PathBuf;
 use tectonic::config::PersistentConfig;
 use tectonic::driver::ProcessingSessionBuilder;
 use tectonic::status::NoopStatusBackend;
@@ -12,8 +11,92 @@ pub enum Error {
     Tectonic(#[from] tectonic::Error),
     #[error("IO failed: {0}")]
     Io(#[from] std::io::Error),
-    #[error("pdftocairo failed")]
-    PdfToCairo(std::process::ExitStatus),
+    #[error("failed to convert PDF to SVG: {0}")]
+    Convert(#[from] pdf::Error),
+    #[error("expected {expected} pages from a batch of TeX sources")]
+    PageCount { expected: usize },
+    #[error("TeX bundle not found at {0}")]
+    BundleNotFound(PathBuf),
+    #[error(
+        "unsupported TeX bundle at {0}, expected a directory or a .zip file (.tar bundles need the index Tectonic downloads alongside them, so unpack one into a directory instead, and .ttb bundles are not supported)"
+    )]
+    UnsupportedBundle(PathBuf),
+    #[error("failed to open TeX bundle at {path}: {message}")]
+    OpenBundle { path: PathBuf, message: String },
+    #[error(
+        "no TeX bundle is configured and the default bundle could not be loaded (it is downloaded on first use, so a local bundle is needed offline): {0}"
+    )]
+    DefaultBundle(tectonic::Error),
+    #[error("failed to create the TeX format cache at {path}: {err}")]
+    FormatCache {
+        path: PathBuf,
+        #[source]
+        err: std::io::Error,
+    },
+}
+
+/// Where Tectonic gets the TeX distribution from, and where it caches formats.
+///
+/// By default, both come from Tectonic's own configuration, which downloads
+/// the bundle on first use.
+///
+/// These are options of a build rather than of a deck: which bundle is
+/// available depends on the machine rendering the decks, not on the decks,
+/// and a bundle chosen per deck would have to be part of every TeX source's
+/// render hash and keep sources of different decks out of the same batch.
+#[derive(Debug, Clone, Default)]
+pub struct TexOptions {
+    /// A local bundle, either an unpacked directory or a `.zip` file.
+    ///
+    /// `.tar` bundles are not supported, as they need the index Tectonic
+    /// downloads alongside them, and `.ttb` bundles need a newer version of
+    /// Tectonic's bundle support than the one Tectonic depends on.
+    pub bundle: Option<PathBuf>,
+    pub format_cache: Option<PathBuf>,
+}
+
+impl TexOptions {
+    fn bundle(
+        &self,
+        config: &PersistentConfig,
+        status: &mut NoopStatusBackend,
+    ) -> Result<Box<dyn tectonic_bundles::Bundle>, Error> {
+        let Some(path) = &self.bundle else {
+            return config
+                .default_bundle(false, status)
+                .map_err(Error::DefaultBundle);
+        };
+
+        if path.is_dir() {
+            Ok(Box::new(tectonic_bundles::dir::DirBundle::new(path)))
+        } else if !path.exists() {
+            Err(Error::BundleNotFound(path.clone()))
+        } else if path.extension().is_some_and(|extension| extension == "zip") {
+            let bundle =
+                tectonic_bundles::zip::ZipBundle::open(path).map_err(|err| Error::OpenBundle {
+                    path: path.clone(),
+                    message: err.to_string(),
+                })?;
+            Ok(Box::new(bundle))
+        } else {
+            Err(Error::UnsupportedBundle(path.clone()))
+        }
+    }
+
+    fn format_cache(&self, config: &PersistentConfig) -> Result<PathBuf, Error> {
+        let Some(path) = &self.format_cache else {
+            return Ok(ctry!(
+                config.format_cache_path();
+                "failed to set up the format cache"
+            ));
+        };
+
+        std::fs::create_dir_all(path).map_err(|err| Error::FormatCache {
+            path: path.clone(),
+            err,
+        })?;
+        Ok(path.clone())
+    }
 }
 
 #[test]
@@ -32,61 +115,141 @@ pub fn render_svg() {
 \draw pic["$x$",draw,angle radius=8,angle eccentricity=1.7] {angle=c--b--a};
 \end{tikzpicture}
 "#;
-    render(source).unwrap();
+    render(
+        source,
+        "",
+        &TexOptions::default(),
+        &pdf::PdfToCairo::default(),
+    )
+    .unwrap();
+}
+
+#[test]
+pub fn render_batch_svgs() {
+    let sources = [r"$x^2$", r"\ce{H2O}", r"\tikz \draw (0,0) -- (1,1);"];
+    let rendered = render_batch(
+        &sources,
+        r"\newcommand{\unused}{}",
+        &TexOptions::default(),
+        &pdf::PdfToCairo::default(),
+    )
+    .unwrap();
+    assert_eq!(rendered.len(), sources.len());
+    assert!(
+        rendered
+            .iter()
+            .all(|svg| String::from_utf8_lossy(svg).contains("<svg"))
+    );
+}
+
+pub fn render(
+    source: &str,
+    preamble: &str,
+    options: &TexOptions,
+    converter: &dyn PdfConverter,
+) -> Result<Vec<u8>, Error> {
+    let (pdf, _) = tex_to_pdf(&document(&[source], preamble), options)?;
+    let svg = converter
+        .page_to_svg(&pdf, 1)?
+        .ok_or(Error::PageCount { expected: 1 })?;
+    Ok(svg)
 }
 
-pub fn render(source: &str) -> Result<String, Error> {
-    let mut process = std::process::Command::new("pdftocairo")
-        .arg("-")
-        .args(["-", "-svg", "-f", "0", "-l", "0"])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .map_err(Error::Io)?;
-
-    let mut stdin = process.stdin.take().expect("child to have stdin");
-
-    let data = tex_to_pdf(source)?;
-    stdin.write_all(&data).map_err(Error::Io)?;
-    stdin.flush().map_err(Error::Io)?;
-    drop(stdin);
-
-    let output = process.wait_with_output().map_err(Error::Io)?;
-    if !output.status.success() {
-        return Err(Error::PdfToCairo(output.status));
+/// Render many sources with a single LaTeX run, as the pages of one
+/// multi-page `standalone` document with the same `preamble`.
+///
+/// Fails if any source fails, or if the sources don't produce exactly one
+/// page each, in which case they have to be rendered on their own to find
+/// out which is at fault.
+pub fn render_batch(
+    sources: &[&str],
+    preamble: &str,
+    options: &TexOptions,
+    converter: &dyn PdfConverter,
+) -> Result<Vec<Vec<u8>>, Error> {
+    let (pdf, sizes) = tex_to_pdf(&document(sources, preamble), options)?;
+    if sizes.len() != sources.len() {
+        return Err(Error::PageCount {
+            expected: sources.len(),
+        });
     }
-    let data = output.stdout;
 
-    let engine = base64::engine::GeneralPurpose::new(
-        &base64::alphabet::STANDARD,
-        base64::engine::GeneralPurposeConfig::new(),
-    );
-    let data = engine.encode(data);
+    let svgs = converter.pages_to_svg(&pdf, &sizes)?;
+    if svgs.len() != sources.len() {
+        return Err(Error::PageCount {
+            expected: sources.len(),
+        });
+    }
+
+    Ok(svgs)
+}
 
-    let mut escaped_source = String::new();
-    pulldown_cmark_escape::escape_html(&mut escaped_source, source).unwrap();
+/// Start of the lines LaTeX logs with the size of each page of a batch, which
+/// are needed to split the pages again after converting them all at once.
+const PAGE_SIZE_MESSAGE: &str = "flashcards page size:";
 
-    Ok(format!(
-        r#"<img src="data:image/svg+xml;base64,{data}" alt="{escaped_source}" title="{escaped_source}" class="w-full h-full tex">"#
-    ))
+/// The size of each page reported in a LaTeX log.
+fn page_sizes(log: &str) -> Vec<pdf::PageSize> {
+    // TeX points are 1/72.27 of an inch, PostScript points 1/72
+    let points =
+        |dimension: &str| Some(dimension.strip_suffix("pt")?.parse::<f64>().ok()? * 72.0 / 72.27);
+
+    log.lines()
+        .filter_map(|line| {
+            let (width, height) = line
+                .strip_prefix(PAGE_SIZE_MESSAGE)?
+                .trim()
+                .split_once(' ')?;
+            Some(pdf::PageSize {
+                width: points(width)?,
+                height: points(height)?,
+            })
+        })
+        .collect()
 }
 
-fn tex_to_pdf(source: &str) -> Result<Vec<u8>, Error> {
-    let tex_input = format!(
+/// A `standalone` document with each of `sources` on its own page, and
+/// `preamble` after the default packages.
+fn document(sources: &[&str], preamble: &str) -> String {
+    let (class_options, environment, body) = if let [source] = sources {
+        ("", String::new(), source.to_string())
+    } else {
+        (
+            "[multi]",
+            format!(
+                "\\newenvironment{{flashcard}}{{}}{{}}\n\\standaloneenv{{flashcard}}\n\\AddToHook{{shipout/before}}{{\\typeout{{{PAGE_SIZE_MESSAGE} \\the\\pdfpagewidth\\space\\the\\pdfpageheight}}}}",
+            ),
+            sources
+                .iter()
+                .map(|source| format!("\\begin{{flashcard}}\n{source}\n\\end{{flashcard}}\n"))
+                .collect::<String>(),
+        )
+    };
+
+    format!(
         r"
-\documentclass{{standalone}}
+\documentclass{class_options}{{standalone}}
 \usepackage{{chemfig}}
 \usepackage{{mhchem}}
 \usepackage{{circuitikz}}
 \usepackage{{tikz}}
 \usepackage{{adjustbox}}
 \usetikzlibrary{{angles,quotes,calc,cd,decorations,decorations.markings,optics,intersections,patterns,shapes.misc}}
+{preamble}
+{environment}
 \begin{{document}}
-{source}
+{body}
 \end{{document}}
 "
-    );
+    )
+}
 
+/// Typeset `tex_input`, returning the PDF and the sizes of its pages that
+/// were reported in the log.
+fn tex_to_pdf(
+    tex_input: &str,
+    options: &TexOptions,
+) -> Result<(Vec<u8>, Vec<pdf::PageSize>), Error> {
     let config = ctry!(
         PersistentConfig::open(false);
         "failed to open config"
@@ -97,18 +260,13 @@ fn tex_to_pdf(source: &str) -> Result<Vec<u8>, Error> {
     let mut files = {
         let mut builder = ProcessingSessionBuilder::default();
         builder
-            .bundle(ctry!(
-                config.default_bundle(false, &mut status);
-                "failed to load the default resource bundle"
-            ))
+            .bundle(options.bundle(&config, &mut status)?)
             .primary_input_buffer(tex_input.as_bytes())
             .tex_input_name("input.tex")
             .format_name("latex")
-            .format_cache_path(ctry!(
-                config.format_cache_path();
-                "failed to set up the format cache"
-            ))
-            .keep_logs(false)
+            .format_cache_path(options.format_cache(&config)?)
+            // kept in memory only, for the page sizes
+            .keep_logs(true)
             .keep_intermediates(false)
             .print_stdout(false)
             .output_format(driver::OutputFormat::Pdf)
@@ -131,6 +289,31 @@ fn tex_to_pdf(source: &str) -> Result<Vec<u8>, Error> {
             "LaTeX didn't report failure, but no output was created (??)"
         ))?
         .data;
+    let sizes = files
+        .remove("input.log")
+        .map(|log| page_sizes(&String::from_utf8_lossy(&log.data)))
+        .unwrap_or_default();
+
+    Ok((data, sizes))
+}
+
+#[test]
+fn page_sizes_are_read_from_log() {
+    let log = "\
+(./input.tex
+LaTeX2e <2023-11-01>
+flashcards page size: 72.27pt 36.135pt
+[1]
+flashcards page size: 10.0pt 20.0pt
+[2] )";
 
-    Ok(data)
+    let sizes = page_sizes(log);
+    assert_eq!(sizes.len(), 2);
+    assert_eq!(
+        sizes[0],
+        pdf::PageSize {
+            width: 72.0,
+            height: 36.0
+        }
+    );
 }
@@ -0,0 +1,116 @@
+//! Theming of the SVGs rendered from TeX and Typst.
+//!
+//! Both draw in black unless told otherwise, and since the SVGs are shown as
+//! images, the page's styles can't reach inside them. So black is replaced
+//! with `currentColor`, and each SVG picks its colour from the reader's colour
+//! scheme, matching the text of the cards in light and dark mode.
+//!
+//! SVGs inlined in Typst's HTML export are part of the page, so there
+//! `currentColor` picks up the colour of the surrounding text instead.
+
+/// How black is written by `pdftocairo`, in attributes or style properties
+/// depending on the version of cairo, and by `typst-svg`.
+const BLACK: &[&str] = &["rgb(0%, 0%, 0%)", "rgb(0%,0%,0%)", "#000000"];
+
+/// Ink colours for light and dark mode, stone-900 and stone-50 in Tailwind's palette.
+const STYLE: &str = "<style>svg{color:#1c1917;fill:currentColor}@media (prefers-color-scheme:dark){svg{color:#fafaf9}}</style>";
+
+/// Draw `svg` in the colour of the reader's colour scheme instead of black.
+pub fn themed(svg: &str) -> String {
+    let mut themed = current_color(svg);
+
+    // the style goes at the start of the root element, where it applies to everything
+    let root_end = themed
+        .find("<svg")
+        .and_then(|start| Some(start + themed[start..].find('>')? + 1));
+    if let Some(root_end) = root_end {
+        themed.insert_str(root_end, STYLE);
+    }
+
+    themed
+}
+
+/// Replace black with `currentColor` in the SVGs inlined in `html`, leaving
+/// the colours of the rest of it, which are the author's, alone.
+pub fn inline_svgs_current_color(html: &str) -> String {
+    let mut replaced = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find("<svg") {
+        let end = svg_end(&rest[start..]).map_or(rest.len(), |end| start + end);
+        replaced.push_str(&rest[..start]);
+        replaced.push_str(&current_color(&rest[start..end]));
+        rest = &rest[end..];
+    }
+    replaced.push_str(rest);
+    replaced
+}
+
+/// The end of the SVG element at the start of `html`, after its closing tag,
+/// including any SVGs nested in it.
+fn svg_end(html: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut position = 0;
+    loop {
+        let open = html[position..].find("<svg").map(|i| position + i);
+        let close = html[position..].find("</svg>").map(|i| position + i)?;
+        match open {
+            Some(open) if open < close => {
+                depth += 1;
+                position = open + "<svg".len();
+            }
+            _ => {
+                depth -= 1;
+                position = close + "</svg>".len();
+                if depth == 0 {
+                    return Some(position);
+                }
+            }
+        }
+    }
+}
+
+/// Replace black in `svg` with `currentColor`.
+fn current_color(svg: &str) -> String {
+    let mut replaced = svg.to_string();
+    for black in BLACK {
+        for prefix in ["=\"", ":"] {
+            replaced = replaced.replace(
+                &format!("{prefix}{black}"),
+                &format!("{prefix}currentColor"),
+            );
+        }
+    }
+    replaced
+}
+
+#[test]
+fn black_follows_theme() {
+    let svg = r##"<?xml version="1.0"?>
+<svg xmlns="http://www.w3.org/2000/svg" width="10pt" height="10pt">
+<path fill="rgb(0%, 0%, 0%)" d="M 0 0"/>
+<path style="stroke:rgb(0%,0%,0%);fill:rgb(100%,100%,100%)" d="M 0 0"/>
+<path fill="#000000" stroke="#0000ff" d="M 0 0"/>
+</svg>"##;
+
+    assert_eq!(
+        themed(svg),
+        format!(
+            r##"<?xml version="1.0"?>
+<svg xmlns="http://www.w3.org/2000/svg" width="10pt" height="10pt">{STYLE}
+<path fill="currentColor" d="M 0 0"/>
+<path style="stroke:currentColor;fill:rgb(100%,100%,100%)" d="M 0 0"/>
+<path fill="currentColor" stroke="#0000ff" d="M 0 0"/>
+</svg>"##
+        )
+    );
+}
+
+#[test]
+fn only_inline_svgs_follow_theme() {
+    let html = r##"<p style="color:#000000">text</p><svg class="typst-frame"><svg><path fill="#000000"/></svg><path fill="#000000"/></svg><span style="color:#000000"></span>"##;
+
+    assert_eq!(
+        inline_svgs_current_color(html),
+        r##"<p style="color:#000000">text</p><svg class="typst-frame"><svg><path fill="currentColor"/></svg><path fill="currentColor"/></svg><span style="color:#000000"></span>"##
+    );
+}
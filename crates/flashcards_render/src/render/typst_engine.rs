@@ -0,0 +1,267 @@
+//! Typst rendering with engines shared by every source in a build.
+//!
+//! Building a [`TypstEngine`] discovers and parses every font, so doing it
+//! once rather than per source is most of the cost of rendering Typst. Each
+//! source is compiled as its own main file, served by [`Files`] under a path
+//! derived from its render hash, so comemo's caches stay valid for sources
+//! that are rendered more than once.
+//!
+//! Fonts and packages are fixed when an engine is built, so decks that
+//! configure their own get an engine per [`Environment`].
+//!
+//! Engines also compile `typst-html` sources with Typst's HTML export, which
+//! is still behind a feature flag in Typst.
+
+use crate::{DeckConfig, DeckDir};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use typst::diag::{FileError, FileResult, PackageError};
+use typst::foundations::Bytes;
+use typst::syntax::{FileId, Source, VirtualPath};
+use typst::{Document, Feature, Features};
+use typst_as_lib::file_resolver::FileResolver;
+use typst_as_lib::typst_kit_options::TypstKitFontOptions;
+use typst_as_lib::{TypstAsLibError, TypstEngine, TypstTemplateCollection};
+
+/// Number of compilations that comemo keeps unused cache entries for.
+const COMEMO_EVICT_MAX_AGE: usize = 32;
+
+/// Namespace of the packages resolved from a deck's package directories.
+const LOCAL_PACKAGE_NAMESPACE: &str = "local";
+
+/// Set up before the preamble of sources rendered as images.
+const SVG_PRELUDE: &str = "#set page(width: auto, height: auto, margin: 1em)";
+
+/// Set up before the preamble of sources exported as HTML, where math and
+/// figures have no HTML of their own, so they are laid out as inline SVGs.
+const HTML_PRELUDE: &str = r#"#show math.equation: html.frame
+#show figure: html.frame"#;
+
+/// The parts of a deck's configuration that an engine is built with.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Environment {
+    packages: Vec<DeckDir>,
+    fonts: Vec<DeckDir>,
+}
+
+impl Environment {
+    pub fn new(config: &DeckConfig) -> Self {
+        Self {
+            packages: config.typst_packages.clone(),
+            fonts: config.typst_fonts.clone(),
+        }
+    }
+}
+
+/// The main file of every source compiled so far, and the package files they used.
+#[derive(Debug, Default)]
+struct Files {
+    sources: RwLock<HashMap<FileId, Source>>,
+    binaries: RwLock<HashMap<FileId, Bytes>>,
+    packages: Vec<PathBuf>,
+}
+
+impl Files {
+    fn insert_main(&self, source: &crate::Source) -> FileId {
+        let path = format!("/card-{:016x}.typ", source.render_hash());
+        let id = FileId::new(None, VirtualPath::new(path));
+
+        let prelude = match source.format {
+            crate::Format::TypstHtml => HTML_PRELUDE,
+            _ => SVG_PRELUDE,
+        };
+        self.sources.write().unwrap().entry(id).or_insert_with(|| {
+            Source::new(
+                id,
+                format!(
+                    "{prelude}\n{}\n{}",
+                    source.config.typst_preamble, source.source
+                ),
+            )
+        });
+
+        id
+    }
+
+    /// The path of a file in a local package, from the nearest package
+    /// directory that has the package.
+    fn package_path(&self, id: FileId) -> FileResult<PathBuf> {
+        let not_found = || FileError::NotFound(id.vpath().as_rootless_path().into());
+        let spec = id.package().ok_or_else(not_found)?;
+
+        if spec.namespace.as_str() != LOCAL_PACKAGE_NAMESPACE {
+            return Err(FileError::Package(PackageError::NotFound(spec.clone())));
+        }
+
+        let root = self
+            .packages
+            .iter()
+            .rev()
+            .map(|dir| dir.join(spec.name.as_str()).join(spec.version.to_string()))
+            .find(|root| root.is_dir())
+            .ok_or_else(|| FileError::Package(PackageError::NotFound(spec.clone())))?;
+
+        id.vpath().resolve(&root).ok_or_else(not_found)
+    }
+}
+
+struct FilesResolver(Arc<Files>);
+
+impl FileResolver for FilesResolver {
+    fn resolve_binary(&self, id: FileId) -> FileResult<Cow<'_, Bytes>> {
+        if let Some(bytes) = self.0.binaries.read().unwrap().get(&id) {
+            return Ok(Cow::Owned(bytes.clone()));
+        }
+
+        let path = self.0.package_path(id)?;
+        let bytes = Bytes::new(std::fs::read(&path).map_err(|err| FileError::from_io(err, &path))?);
+        self.0.binaries.write().unwrap().insert(id, bytes.clone());
+
+        Ok(Cow::Owned(bytes))
+    }
+
+    fn resolve_source(&self, id: FileId) -> FileResult<Cow<'_, Source>> {
+        if let Some(source) = self.0.sources.read().unwrap().get(&id) {
+            return Ok(Cow::Owned(source.clone()));
+        }
+
+        let path = self.0.package_path(id)?;
+        let text = std::fs::read_to_string(&path).map_err(|err| FileError::from_io(err, &path))?;
+        let source = Source::new(id, text);
+        self.0.sources.write().unwrap().insert(id, source.clone());
+
+        Ok(Cow::Owned(source))
+    }
+}
+
+pub struct Engine {
+    engine: TypstEngine<TypstTemplateCollection>,
+    files: Arc<Files>,
+}
+
+impl Engine {
+    pub fn new(environment: &Environment) -> Self {
+        let files = Arc::new(Files {
+            packages: environment
+                .packages
+                .iter()
+                .map(|dir| dir.path.clone())
+                .collect(),
+            ..Files::default()
+        });
+
+        let fonts = TypstKitFontOptions::new()
+            .include_embedded_fonts(true)
+            .include_dirs(environment.fonts.iter().map(|dir| dir.path.clone()));
+
+        let engine = TypstEngine::builder()
+            .search_fonts_with(fonts)
+            .add_file_resolver(FilesResolver(Arc::clone(&files)))
+            .comemo_evict_max_age(Some(COMEMO_EVICT_MAX_AGE))
+            .features(Features::from_iter([Feature::Html]))
+            .build();
+
+        Self { engine, files }
+    }
+
+    /// Compile `source` to a document of type `D`, which is an
+    /// [`HtmlDocument`](typst::html::HtmlDocument) for `typst-html` sources
+    /// and a [`PagedDocument`](typst::layout::PagedDocument) for the rest.
+    pub fn compile<D: Document>(&self, source: &crate::Source) -> Result<D, TypstAsLibError> {
+        let id = self.files.insert_main(source);
+        self.engine.compile(id).output
+    }
+}
+
+#[test]
+fn compiled_packages_are_reused() {
+    let root = std::env::temp_dir().join(format!("flashcards-reuse-{}", std::process::id()));
+    let package = root.join("sum/0.1.0");
+    std::fs::create_dir_all(&package).unwrap();
+    std::fs::write(
+        package.join("typst.toml"),
+        "[package]\nname = \"sum\"\nversion = \"0.1.0\"\nentrypoint = \"lib.typ\"\n",
+    )
+    .unwrap();
+    std::fs::write(
+        package.join("lib.typ"),
+        // loops aren't memoized, unlike function calls, so this is slow to evaluate
+        "#let value = { let sum = 0; for i in range(300000) { sum += i }; sum }",
+    )
+    .unwrap();
+
+    let config = Arc::new(DeckConfig {
+        typst_packages: vec![DeckDir {
+            path: root.clone(),
+            hash: 0,
+        }],
+        ..Default::default()
+    });
+    let source = |text: &str| crate::Source {
+        source: format!("#import \"@local/sum:0.1.0\": value\n{text}"),
+        format: crate::Format::Typst,
+        config: Arc::clone(&config),
+    };
+    let engine = Engine::new(&Environment::new(&config));
+
+    let start = std::time::Instant::now();
+    let first = engine.compile::<typst::layout::PagedDocument>(&source("#value"));
+    let first_time = start.elapsed();
+
+    // the package is gone from disk, so the second source can only compile
+    // with the files and evaluated module kept from the first
+    std::fs::remove_dir_all(&root).unwrap();
+    let start = std::time::Instant::now();
+    let second = engine.compile::<typst::layout::PagedDocument>(&source("#(value + 1)"));
+    let second_time = start.elapsed();
+
+    assert!(first.is_ok());
+    assert!(second.is_ok());
+    assert!(
+        second_time * 4 < first_time,
+        "second compile took {second_time:?}, first took {first_time:?}"
+    );
+}
+
+#[cfg(test)]
+fn package_file(spec: &str, path: &str) -> FileId {
+    FileId::new(Some(spec.parse().unwrap()), VirtualPath::new(path))
+}
+
+#[test]
+fn local_packages_are_resolved() {
+    let root = std::env::temp_dir().join(format!("flashcards-packages-{}", std::process::id()));
+    let outer = root.join("outer");
+    let inner = root.join("inner");
+    for package in [
+        outer.join("shared/0.1.0"),
+        outer.join("outer-only/0.1.0"),
+        inner.join("shared/0.1.0"),
+    ] {
+        std::fs::create_dir_all(&package).unwrap();
+    }
+
+    let files = Files {
+        packages: vec![outer.clone(), inner.clone()],
+        ..Files::default()
+    };
+    let shared = files.package_path(package_file("@local/shared:0.1.0", "lib.typ"));
+    let outer_only = files.package_path(package_file("@local/outer-only:0.1.0", "lib.typ"));
+    let missing = files.package_path(package_file("@local/shared:0.2.0", "lib.typ"));
+    let preview = files.package_path(package_file("@preview/shared:0.1.0", "lib.typ"));
+    std::fs::remove_dir_all(&root).unwrap();
+
+    // packages in nearer deck directories shadow those further up
+    assert_eq!(shared.unwrap(), inner.join("shared/0.1.0/lib.typ"));
+    assert_eq!(outer_only.unwrap(), outer.join("outer-only/0.1.0/lib.typ"));
+    assert!(matches!(
+        missing,
+        Err(FileError::Package(PackageError::NotFound(_)))
+    ));
+    assert!(matches!(
+        preview,
+        Err(FileError::Package(PackageError::NotFound(_)))
+    ));
+}
@@ -0,0 +1,377 @@
+//! Rendering in worker processes, so that a source can be given a time and
+//! memory limit, and a source that crashes TeX or Typst only fails itself,
+//! rather than the process rendering everything else. Neither TeX nor Typst
+//! can be interrupted, so killing the process running them is the only way
+//! to stop a source that never finishes.
+//!
+//! A worker reads requests from its stdin and writes responses to its
+//! stdout, one JSON document per line. Each request is a list of sources, and
+//! each response the result of rendering every one of them, in order, with
+//! errors reduced to their messages.
+//!
+//! Workers are started as they are needed, so there is one for each request
+//! in flight, and are reused until they crash or take longer than the
+//! [time limit](WorkerPool::with_time_limit), when they are killed.
+
+use super::{Error, Renderer};
+use crate::{Asset, Source};
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+/// The result of rendering a source, as sent by a worker.
+type Response = Result<(String, Vec<Asset>), String>;
+
+/// Serve the requests read from `input` with `renderer`, writing the
+/// responses to `output`, until `input` ends.
+pub fn serve(
+    renderer: &Renderer,
+    input: impl BufRead,
+    mut output: impl Write,
+) -> std::io::Result<()> {
+    for line in input.lines() {
+        let sources: Vec<Source> = serde_json::from_str(&line?)?;
+        let responses = renderer
+            .render_batched(sources)
+            .into_iter()
+            .map(|rendered| {
+                rendered
+                    .map(|rendered| (rendered.html, rendered.assets))
+                    .map_err(|err| err.to_string())
+            })
+            .collect::<Vec<Response>>();
+
+        serde_json::to_writer(&mut output, &responses)?;
+        output.write_all(b"\n")?;
+        output.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Why a request to a worker failed, before it is attributed to a source.
+enum Failure {
+    /// The worker took longer than the time limit, and was killed.
+    Timeout(Duration),
+    /// The worker exited before responding.
+    Exited(ExitStatus),
+    Error(Error),
+}
+
+impl Failure {
+    /// The error for rendering the source at `path` failing this way.
+    fn at(self, path: &Path) -> Error {
+        match self {
+            Self::Timeout(limit) => Error::Timeout {
+                path: path.to_path_buf(),
+                limit,
+            },
+            Self::Exited(status) => Error::WorkerCrashed {
+                path: path.to_path_buf(),
+                status,
+            },
+            Self::Error(err) => err,
+        }
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(err: Error) -> Self {
+        Self::Error(err)
+    }
+}
+
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    /// Lines written by the worker, read on a thread of their own so that
+    /// waiting for them can time out.
+    responses: Receiver<String>,
+}
+
+impl Worker {
+    fn spawn(program: &PathBuf, args: &[OsString]) -> Result<Self, Error> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(Error::WorkerIo)?;
+
+        let stdin = child.stdin.take().expect("child to have stdin");
+        let stdout = BufReader::new(child.stdout.take().expect("child to have stdout"));
+
+        let (sender, responses) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in stdout.lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            child,
+            stdin,
+            responses,
+        })
+    }
+
+    fn request(
+        &mut self,
+        sources: &[&Source],
+        time_limit: Option<Duration>,
+    ) -> Result<Vec<Response>, Failure> {
+        let mut request = serde_json::to_vec(sources).map_err(Error::from)?;
+        request.push(b'\n');
+        if self
+            .stdin
+            .write_all(&request)
+            .and_then(|()| self.stdin.flush())
+            .is_err()
+        {
+            return Err(self.exited());
+        }
+
+        let line = match time_limit {
+            Some(limit) => match self.responses.recv_timeout(limit) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => return Err(Failure::Timeout(limit)),
+                Err(RecvTimeoutError::Disconnected) => return Err(self.exited()),
+            },
+            None => match self.responses.recv() {
+                Ok(line) => line,
+                Err(_) => return Err(self.exited()),
+            },
+        };
+
+        Ok(serde_json::from_str(&line).map_err(Error::from)?)
+    }
+
+    /// The failure of a worker that stopped responding, with how it exited.
+    fn exited(&mut self) -> Failure {
+        match self.child.wait() {
+            Ok(status) => Failure::Exited(status),
+            Err(err) => Failure::Error(Error::WorkerIo(err)),
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        _ = self.child.kill();
+        _ = self.child.wait();
+    }
+}
+
+/// Worker processes shared by every source rendered in a build.
+pub struct WorkerPool {
+    program: PathBuf,
+    args: Vec<OsString>,
+    time_limit: Option<Duration>,
+    idle: Mutex<Vec<Worker>>,
+}
+
+impl WorkerPool {
+    /// A pool of workers that run `program` with `args`, which should
+    /// [`serve`] requests on its stdin and stdout.
+    pub fn new(
+        program: impl Into<PathBuf>,
+        args: impl IntoIterator<Item = impl Into<OsString>>,
+    ) -> Self {
+        Self {
+            program: program.into(),
+            args: args.into_iter().map(Into::into).collect(),
+            time_limit: None,
+            idle: Mutex::default(),
+        }
+    }
+
+    /// Kill workers that take longer than `limit` to respond, failing the
+    /// sources they were rendering.
+    pub fn with_time_limit(mut self, limit: Duration) -> Self {
+        self.time_limit = Some(limit);
+        self
+    }
+
+    /// Render `sources` in one worker, or each in a worker of its own if
+    /// that fails, so that a crash or timeout is reported for the source that
+    /// caused it.
+    ///
+    /// The batch gets the time limit of a single source, so that a source
+    /// that never finishes costs at most twice the limit before it is found.
+    pub(super) fn render_many(
+        &self,
+        sources: &[(Source, PathBuf)],
+    ) -> Vec<Result<(String, Vec<Asset>), Error>> {
+        let batch = sources.iter().map(|(source, _)| source).collect::<Vec<_>>();
+        if batch.len() > 1
+            && let Ok(responses) = self.request(&batch)
+        {
+            return responses
+                .into_iter()
+                .map(|response| response.map_err(Error::Worker))
+                .collect();
+        }
+
+        sources
+            .iter()
+            .map(|(source, path)| self.render(source, path))
+            .collect()
+    }
+
+    /// Render `source`, from the card at `path`, retrying once in a new
+    /// worker if its worker crashes.
+    pub(super) fn render(
+        &self,
+        source: &Source,
+        path: &Path,
+    ) -> Result<(String, Vec<Asset>), Error> {
+        let sources = [source];
+        let responses = match self.request(&sources) {
+            Err(Failure::Exited(_)) => self.request(&sources),
+            responses => responses,
+        }
+        .map_err(|failure| failure.at(path))?;
+
+        responses
+            .into_iter()
+            .next()
+            .expect("a response for every source")
+            .map_err(Error::Worker)
+    }
+
+    /// Send `sources` to an idle worker, or a new one if none are idle. The
+    /// worker is killed if the request fails.
+    fn request(&self, sources: &[&Source]) -> Result<Vec<Response>, Failure> {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut worker = match idle {
+            Some(worker) => worker,
+            None => Worker::spawn(&self.program, &self.args)?,
+        };
+
+        let responses = worker.request(sources, self.time_limit)?;
+        self.idle.lock().unwrap().push(worker);
+
+        Ok(responses)
+    }
+}
+
+#[cfg(test)]
+fn markdown_source(text: &str) -> Source {
+    Source {
+        source: text.to_string(),
+        format: crate::Format::Markdown,
+        config: Default::default(),
+    }
+}
+
+#[test]
+fn serve_renders_requests() {
+    let request =
+        serde_json::to_string(&[markdown_source("*hi*"), markdown_source("$\\frac{1}$")]).unwrap();
+
+    let mut output = Vec::new();
+    serve(
+        &Renderer::new(),
+        format!("{request}\n").as_bytes(),
+        &mut output,
+    )
+    .unwrap();
+
+    let responses: Vec<Response> = serde_json::from_slice(&output).unwrap();
+    assert_eq!(
+        responses[0],
+        Ok(("<div><p><em>hi</em></p>\n</div>".to_string(), Vec::new()))
+    );
+    assert!(responses[1].as_ref().unwrap_err().contains("KaTeX error"));
+}
+
+#[test]
+fn crashing_source_fails_alone() {
+    // a stand-in for a worker, which crashes on any request containing `crash`
+    let pool = WorkerPool::new(
+        "sh",
+        [
+            "-c",
+            r#"while read -r line; do
+                case "$line" in *crash*) exit 3;; esac
+                echo '[{"Ok":["<p>fine</p>",[]]}]'
+            done"#,
+        ],
+    );
+
+    let rendered = pool.render_many(&[
+        (markdown_source("fine"), PathBuf::from("fine.toml")),
+        (markdown_source("crash"), PathBuf::from("crash.toml")),
+    ]);
+    assert_eq!(
+        rendered[0].as_ref().unwrap(),
+        &("<p>fine</p>".to_string(), Vec::new())
+    );
+    assert!(matches!(
+        &rendered[1],
+        Err(Error::WorkerCrashed { path, status })
+            if path == Path::new("crash.toml") && status.code() == Some(3)
+    ));
+}
+
+#[test]
+fn slow_worker_is_killed() {
+    let pool = WorkerPool::new("sh", ["-c", "read -r line; sleep 10"])
+        .with_time_limit(Duration::from_millis(50));
+
+    let rendered = pool.render(&markdown_source("slow"), Path::new("slow.toml"));
+    assert!(matches!(
+        rendered,
+        Err(Error::Timeout { path, .. }) if path == Path::new("slow.toml")
+    ));
+    assert!(pool.idle.lock().unwrap().is_empty());
+}
+
+#[test]
+fn slow_batch_is_split_within_one_limit() {
+    // a stand-in for a worker, which takes longer than the limit for a batch
+    // but answers a single source immediately
+    let pool = WorkerPool::new(
+        "sh",
+        [
+            "-c",
+            r#"while read -r line; do
+                case "$line" in *'},{'*) sleep 10;; esac
+                echo '[{"Ok":["<p>alone</p>",[]]}]'
+            done"#,
+        ],
+    )
+    .with_time_limit(Duration::from_millis(500));
+
+    let start = std::time::Instant::now();
+    let rendered = pool.render_many(&[
+        (markdown_source("a"), PathBuf::from("a.toml")),
+        (markdown_source("b"), PathBuf::from("b.toml")),
+    ]);
+    assert!(start.elapsed() < Duration::from_secs(2));
+    for rendered in rendered {
+        assert_eq!(rendered.unwrap(), ("<p>alone</p>".to_string(), Vec::new()));
+    }
+}
+
+#[test]
+fn asset_data_is_base64() {
+    let response: Response = Ok((
+        String::new(),
+        vec![Asset {
+            mime: "image/svg+xml".to_string(),
+            data: b"<svg/>".to_vec(),
+        }],
+    ));
+
+    let json = serde_json::to_string(&response).unwrap();
+    assert!(json.contains(r#""data":"PHN2Zy8+""#));
+    assert_eq!(serde_json::from_str::<Response>(&json).unwrap(), response);
+}
@@ -1,25 +1,33 @@
 use clap::Parser;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use flashcards_render::CardKind;
+use flashcards_render::hash::ContentHasher;
+use flashcards_render::render::pdf::PdfToCairo;
+use flashcards_render::render::worker::WorkerPool;
+use flashcards_render::render::{Renderer, TexOptions};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
 use itertools::Itertools;
-use sqlx::SqlitePool;
 use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::{QueryBuilder, SqlitePool};
 use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::ffi::OsString;
 use std::fmt::Display;
-use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
+
+mod rekey;
 
 #[derive(Debug, thiserror::Error)]
 enum Error {
     #[error("error loading cards: {0}")]
     Io(#[from] std::io::Error),
-    #[error("error deserializing cards at {path}: {err}")]
-    Deserialize {
-        path: PathBuf,
-        #[source]
-        err: toml::de::Error,
-    },
+    #[error("error loading cards: {0}")]
+    Load(#[from] flashcards_render::loader::LoadError),
+    #[error("error deserializing cards: {0}")]
+    Deserialize(#[from] flashcards_render::loader::DeserializeError),
     #[error("error rendering card at {path}: {err}")]
     Render {
         path: PathBuf,
@@ -30,12 +38,95 @@ enum Error {
     Sqlx(#[from] sqlx::Error),
     #[error("invalid utf8 in path")]
     NonUtf8Path(PathBuf),
+    #[error("unknown format in database: {0}")]
+    UnknownFormat(#[from] flashcards_render::UnknownFormat),
 }
 
 #[derive(Debug)]
 struct Card<T> {
     card: flashcards_render::Card<T>,
     path: Arc<PathBuf>,
+    root: Arc<str>,
+}
+
+/// A directory of decks, with an optional namespace for its topics.
+#[derive(Debug, Clone)]
+struct Root {
+    namespace: Option<Arc<str>>,
+    path: PathBuf,
+}
+
+impl FromStr for Root {
+    type Err = Infallible;
+
+    /// Parse `NAMESPACE=PATH` or `PATH`. Only the text before the first `=` is
+    /// taken as a namespace, and only if it is a single topic segment, so
+    /// `./a=b` is the path `a=b`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.split_once('=') {
+            Some((namespace, path)) if !namespace.is_empty() && !namespace.contains('/') => Self {
+                namespace: Some(Arc::from(namespace)),
+                path: PathBuf::from(path),
+            },
+            _ => Self {
+                namespace: None,
+                path: PathBuf::from(s),
+            },
+        })
+    }
+}
+
+/// The key cards indexed before roots were keyed by their canonical path were
+/// stored under, which was the path of the `data` directory as given.
+const LEGACY_ROOT_KEY: &str = "data";
+
+impl Root {
+    /// The key cards from this root are stored under, which is its canonical
+    /// path so that the same directory has one key wherever it is built from.
+    fn key(&self) -> Result<String, Error> {
+        let path = std::fs::canonicalize(&self.path)?;
+        path.into_os_string()
+            .into_string()
+            .map_err(|path| Error::NonUtf8Path(path.into()))
+    }
+
+    /// The keys of the cards from this root, including the legacy key if this
+    /// root is the `data` directory that cards were loaded from before.
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        let key = self.key()?;
+        let legacy = std::fs::canonicalize(LEGACY_ROOT_KEY)
+            .is_ok_and(|path| path.to_str() == Some(&key))
+            .then(|| LEGACY_ROOT_KEY.to_string());
+
+        Ok(std::iter::once(key).chain(legacy).collect())
+    }
+}
+
+/// Errors in individual decks or cards, which either abort the run or, with
+/// `--keep-going`, are collected and reported once everything else is built.
+struct CardErrors {
+    keep_going: bool,
+    errors: Vec<Error>,
+}
+
+impl CardErrors {
+    fn new(keep_going: bool) -> Self {
+        Self {
+            keep_going,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Collect `err` if it only affects some cards and `--keep-going` was passed, otherwise return it.
+    fn push(&mut self, err: Error) -> Result<(), Error> {
+        match err {
+            Error::Load(_) | Error::Deserialize(_) | Error::Render { .. } if self.keep_going => {
+                self.errors.push(err);
+                Ok(())
+            }
+            err => Err(err),
+        }
+    }
 }
 
 enum SectionTitleState {
@@ -67,11 +158,10 @@ async fn render_source_cached(
     source: flashcards_render::Source,
     path: impl AsRef<Path>,
     pool: &SqlitePool,
+    renderer: &Arc<Renderer>,
     progress: &ProgressBar,
 ) -> Result<i64, Error> {
-    let mut hasher = std::hash::DefaultHasher::new();
-    source.hash(&mut hasher);
-    let hash = i64::from_ne_bytes(hasher.finish().to_ne_bytes());
+    let hash = source.render_hash();
 
     if sqlx::query!("SELECT hash FROM rendered WHERE hash = (?)", hash)
         .fetch_optional(pool)
@@ -84,27 +174,149 @@ async fn render_source_cached(
     let path = path.as_ref().to_path_buf();
     progress.set_message(format!(": {} at {}", source.format, path.to_string_lossy()));
 
-    let rendered: flashcards_render::Rendered = tokio_rayon::spawn(move || source.try_into())
-        .await
-        .map_err(|err| Error::Render { path, err })?;
+    let renderer = Arc::clone(renderer);
+    let rendered = {
+        let path = path.clone();
+        tokio_rayon::spawn(move || renderer.render(source, &path))
+    }
+    .await
+    .map_err(|err| Error::Render { path, err })?;
+
+    insert_rendered(pool, hash, &rendered).await?;
+
+    Ok(hash)
+}
 
+async fn insert_rendered(
+    pool: &SqlitePool,
+    hash: i64,
+    rendered: &flashcards_render::Rendered,
+) -> Result<(), Error> {
     let format = rendered.source.format.to_string();
+    let text = flashcards_render::render::plain_text(&rendered.source);
+
+    // a source is only cached along with all of its assets, so that a build
+    // interrupted between inserts renders it again rather than serving
+    // missing assets
+    let mut tx = pool.begin().await?;
 
     sqlx::query!(
-        "INSERT OR IGNORE INTO rendered (hash, source, html, render_format) VALUES (?, ?, ?, ?)",
+        "INSERT OR IGNORE INTO rendered (hash, source, html, render_format, text) VALUES (?, ?, ?, ?, ?)",
         hash,
         rendered.source.source,
         rendered.html,
         format,
+        text,
     )
-    .execute(pool)
+    .execute(&mut *tx)
     .await?;
 
-    Ok(hash)
+    for asset in rendered.assets.iter() {
+        let asset_hash = asset.content_hash();
+
+        sqlx::query!(
+            "INSERT OR IGNORE INTO asset (hash, mime, data) VALUES (?, ?, ?)",
+            asset_hash,
+            asset.mime,
+            asset.data,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "INSERT OR IGNORE INTO rendered_asset (rendered, asset) VALUES (?, ?)",
+            hash,
+            asset_hash,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Number of TeX sources typeset together in one LaTeX run.
+const TEX_BATCH_SIZE: usize = 32;
+
+/// Render every uncached TeX source in `cards` in batches, which is much
+/// faster than starting a LaTeX run per source.
+///
+/// Sources that fail are reported to `errors` with the path of a card they
+/// belong to, and their hashes returned, so that their cards can be skipped
+/// rather than rendering them again.
+async fn render_tex_batches(
+    pool: &Arc<SqlitePool>,
+    renderer: &Arc<Renderer>,
+    cards: &[Card<flashcards_render::Source>],
+    errors: &mut CardErrors,
+    progress: &ProgressBar,
+) -> Result<HashSet<i64>, Error> {
+    let cached = sqlx::query!("SELECT hash FROM rendered")
+        .fetch_all(pool.as_ref())
+        .await?
+        .into_iter()
+        .map(|record| record.hash)
+        .collect::<HashSet<_>>();
+
+    let sources = cards
+        .iter()
+        .flat_map(|card| {
+            [&card.card.term, &card.card.definition].map(|source| (source, &card.path))
+        })
+        .filter(|(source, _)| source.format == flashcards_render::Format::Tex)
+        .map(|(source, path)| (source.render_hash(), source, path))
+        .filter(|(hash, _, _)| !cached.contains(hash))
+        .unique_by(|(hash, _, _)| *hash)
+        .map(|(_, source, path)| (source.clone(), path.to_path_buf()))
+        // only sources with the same preamble can share a LaTeX run
+        .sorted_by(|(a, _), (b, _)| a.config.tex_preamble.cmp(&b.config.tex_preamble))
+        .collect_vec();
+
+    if sources.is_empty() {
+        return Ok(HashSet::new());
+    }
+
+    progress.set_message(format!(": {} tex sources in batches", sources.len()));
+
+    let mut batches = tokio::task::JoinSet::new();
+    for batch in sources.into_iter().chunks(TEX_BATCH_SIZE).into_iter() {
+        let batch = batch.collect_vec();
+        let paths = batch
+            .iter()
+            .map(|(source, path)| (source.render_hash(), path.clone()))
+            .collect_vec();
+        let renderer = Arc::clone(renderer);
+        batches.spawn(tokio_rayon::spawn(move || {
+            (paths, renderer.render_many(batch))
+        }));
+    }
+
+    let mut failed = HashSet::new();
+    while let Some(rendered) = batches.join_next().await {
+        // the sources of a batch that panicked are rendered on their own with
+        // their cards instead
+        let Ok((paths, rendered)) = rendered else {
+            continue;
+        };
+        for ((hash, path), rendered) in paths.into_iter().zip(rendered) {
+            match rendered {
+                Ok(rendered) => insert_rendered(pool, hash, &rendered).await?,
+                Err(err) => {
+                    errors.push(Error::Render { path, err })?;
+                    failed.insert(hash);
+                }
+            }
+        }
+    }
+
+    Ok(failed)
 }
 
 async fn load(
-    path: impl AsRef<Path>,
+    roots: &[Root],
+    errors: &mut CardErrors,
     progress: &MultiProgress,
 ) -> Result<Vec<Card<flashcards_render::Source>>, Error> {
     let load_progess = ProgressBar::new_spinner()
@@ -112,27 +324,32 @@ async fn load(
         .with_message(section_title("Loading", SectionTitleState::Processing));
     let load_progress = progress.add(load_progess);
 
-    let cards = flashcards_render::loader::load_dir(path)
-        .map(|result| -> Result<_, Error> {
+    let mut cards = Vec::new();
+    for root in roots {
+        let key = Arc::<str>::from(root.key()?);
+
+        for file_contents in flashcards_render::loader::load_dir(&root.path, root.namespace.clone())
+        {
             load_progress.inc(1);
 
-            let file_contents = result.map_err(Error::Io)?;
-            let path = file_contents.path.clone();
-            let cards = file_contents
-                .into_cards()
-                .map_err(|err| Error::Deserialize {
-                    path: path.clone(),
-                    err,
-                })?;
-
-            let path = Arc::new(path);
-            Ok(cards.into_iter().map(move |card| Card {
-                card,
-                path: Arc::clone(&path),
-            }))
-        })
-        .flatten_ok()
-        .collect::<Result<Vec<_>, _>>()?;
+            let file_contents = match file_contents {
+                Ok(file_contents) => file_contents,
+                Err(err) => {
+                    errors.push(err.into())?;
+                    continue;
+                }
+            };
+            let path = Arc::new(file_contents.path.clone());
+            match file_contents.into_cards() {
+                Ok(file_cards) => cards.extend(file_cards.into_iter().map(|card| Card {
+                    card,
+                    path: Arc::clone(&path),
+                    root: Arc::clone(&key),
+                })),
+                Err(err) => errors.push(err.into())?,
+            }
+        }
+    }
 
     load_progress.finish();
     progress.remove(&load_progress);
@@ -142,15 +359,24 @@ async fn load(
 }
 
 struct RenderedCard {
+    kind: CardKind,
+    /// Hashes of the rendered sides.
     term: i64,
     definition: i64,
+    /// Content hashes of the sides, which identify the card independently of
+    /// the deck configuration it was rendered with.
+    term_content: i64,
+    definition_content: i64,
     topics: HashSet<Arc<flashcards_render::Topic>>,
     path: Arc<PathBuf>,
+    root: Arc<str>,
 }
 
 async fn render(
     pool: Arc<SqlitePool>,
+    renderer: Arc<Renderer>,
     cards: Vec<Card<flashcards_render::Source>>,
+    errors: &mut CardErrors,
     progress: &MultiProgress,
 ) -> Result<Vec<RenderedCard>, Error> {
     let render_progress = ProgressBar::new(
@@ -162,19 +388,40 @@ async fn render(
     .with_style(bar_style("Rendering"));
     let render_progress = progress.add(render_progress);
 
+    let failed = render_tex_batches(&pool, &renderer, &cards, errors, &render_progress).await?;
+
     let mut render_jobs = tokio::task::JoinSet::<Result<_, Error>>::new();
     for card in cards {
+        // the errors of sources that failed in a batch have been reported already
+        if [&card.card.term, &card.card.definition]
+            .iter()
+            .any(|source| failed.contains(&source.render_hash()))
+        {
+            render_progress.inc(1);
+            continue;
+        }
+
         let render_progress = render_progress.clone();
         let pool = Arc::clone(&pool);
+        let renderer = Arc::clone(&renderer);
         render_jobs.spawn(async move {
-            let term =
-                render_source_cached(card.card.term, card.path.as_path(), &pool, &render_progress)
-                    .await?;
+            let term_content = card.card.term.content_hash();
+            let definition_content = card.card.definition.content_hash();
+
+            let term = render_source_cached(
+                card.card.term,
+                card.path.as_path(),
+                &pool,
+                &renderer,
+                &render_progress,
+            )
+            .await?;
 
             let definition = render_source_cached(
                 card.card.definition,
                 card.path.as_path(),
                 &pool,
+                &renderer,
                 &render_progress,
             )
             .await?;
@@ -183,17 +430,23 @@ async fn render(
 
             Ok(RenderedCard {
                 path: card.path,
+                root: card.root,
                 topics: card.card.topics,
+                kind: card.card.kind,
                 term,
                 definition,
+                term_content,
+                definition_content,
             })
         });
     }
 
     let mut cards = Vec::new();
     while let Some(result) = render_jobs.join_next().await {
-        let card = result.expect("render job not to panic or be cancelled")?;
-        cards.push(card);
+        match result.expect("render job not to panic or be cancelled") {
+            Ok(card) => cards.push(card),
+            Err(err) => errors.push(err)?,
+        }
     }
 
     progress.remove(&render_progress);
@@ -202,6 +455,42 @@ async fn render(
     Ok(cards)
 }
 
+/// The identity of a card, from its sides, where it was loaded from and its topics.
+///
+/// Reversed cards are distinguished from basic ones, so that a reversed card
+/// with the same term and definition is still a separate card.
+fn card_hash(
+    term: i64,
+    definition: i64,
+    path: &str,
+    kind: CardKind,
+    topics: impl IntoIterator<Item = i64>,
+) -> i64 {
+    let mut hasher = ContentHasher::new("card");
+    hasher.write_i64(term);
+    hasher.write_i64(definition);
+    hasher.write_str(path);
+    if kind == CardKind::Reversed {
+        hasher.write_str("reversed");
+    }
+    for topic in topics.into_iter().sorted() {
+        hasher.write_i64(topic);
+    }
+    hasher.finish()
+}
+
+/// Hash of the set of cards in a topic, used to skip reindexing unchanged topics.
+fn topic_cards_hash(cards: impl IntoIterator<Item = i64>) -> i64 {
+    let mut hasher = ContentHasher::new("topic-cards");
+    for card in cards.into_iter().sorted() {
+        hasher.write_i64(card);
+    }
+    hasher.finish()
+}
+
+/// Rows per `card_topic` insert, well under SQLite's limit on bound parameters.
+const CARD_TOPIC_BATCH_SIZE: usize = 500;
+
 #[derive(Debug)]
 struct TopicData {
     cards: HashSet<i64>,
@@ -213,7 +502,9 @@ struct TopicData {
 
 async fn index(
     pool: &Arc<SqlitePool>,
+    roots: &[Root],
     cards: &[RenderedCard],
+    keep_orphans: i64,
     progress: &MultiProgress,
 ) -> Result<(), Error> {
     let index_progress = ProgressBar::new(
@@ -225,6 +516,34 @@ async fn index(
     .with_style(bar_style("Indexing"));
     let index_progress = progress.add(index_progress);
 
+    // readers see either the previous build or this one, never a partial index
+    let mut tx = pool.begin().await?;
+
+    index_progress.set_message(": search");
+
+    // sources rendered before search existed have no plain text yet
+    let unindexed =
+        sqlx::query!("SELECT hash, render_format, source FROM rendered WHERE text IS NULL")
+            .fetch_all(&mut *tx)
+            .await?;
+
+    for record in unindexed {
+        let source = flashcards_render::Source {
+            format: record.render_format.parse()?,
+            source: record.source,
+            config: Default::default(),
+        };
+        let text = flashcards_render::render::plain_text(&source);
+
+        sqlx::query!(
+            "UPDATE rendered SET text = ? WHERE hash = ?",
+            text,
+            record.hash
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
     index_progress.set_message(": cards");
 
     let compiled_time = chrono::Utc::now();
@@ -232,49 +551,50 @@ async fn index(
     let mut topic_data = HashMap::new();
 
     let existing_card_hashes = sqlx::query!("SELECT hash FROM card")
-        .fetch_all(pool.as_ref())
+        .fetch_all(&mut *tx)
         .await?
         .into_iter()
         .map(|record| record.hash)
         .collect::<HashSet<i64>>();
 
     for card in cards.iter() {
-        let mut card_hasher = std::hash::DefaultHasher::new();
-        card.term.hash(&mut card_hasher);
-        card.definition.hash(&mut card_hasher);
-        card.path.hash(&mut card_hasher);
+        let path = card
+            .path
+            .to_str()
+            .ok_or_else(|| Error::NonUtf8Path(card.path.to_path_buf()))?;
 
         let mut topic_hashes = HashSet::new();
-        for topic in card.topics.iter().sorted() {
-            let mut topic_hasher = std::hash::DefaultHasher::new();
-            for segment in topic.0.iter().take(topic.0.len() - 1) {
-                segment.hash(&mut topic_hasher);
-            }
-
-            let name = topic.0.last().expect("topic to have at least 1 segment");
-
-            let topic_parent_hash = if topic.0.len() > 1 {
-                Some(i64::from_ne_bytes(topic_hasher.finish().to_ne_bytes()))
-            } else {
-                None
-            };
-
-            name.hash(&mut topic_hasher);
-            let topic_hash = i64::from_ne_bytes(topic_hasher.finish().to_ne_bytes());
+        for topic in card.topics.iter() {
+            let topic_hash = topic.content_hash();
 
             topic_data.entry(topic_hash).or_insert(TopicData {
                 cards: HashSet::new(),
-                parent: topic_parent_hash,
-                name: Arc::clone(name),
+                parent: topic.parent_content_hash(),
+                name: Arc::clone(topic.0.last().expect("topic to have at least 1 segment")),
                 full_name: topic.0.join("/"),
                 length: topic.0.len(),
             });
 
             topic_hashes.insert(topic_hash);
-            topic_hash.hash(&mut card_hasher);
         }
 
-        let hash = i64::from_ne_bytes(card_hasher.finish().to_ne_bytes());
+        let hash = card_hash(
+            card.term_content,
+            card.definition_content,
+            path,
+            card.kind,
+            topic_hashes.iter().copied(),
+        );
+
+        let reverse_of = (card.kind == CardKind::Reversed).then(|| {
+            card_hash(
+                card.definition_content,
+                card.term_content,
+                path,
+                CardKind::Basic,
+                topic_hashes.iter().copied(),
+            )
+        });
 
         for topic in topic_hashes {
             topic_data.entry(topic).and_modify(|data| {
@@ -282,31 +602,35 @@ async fn index(
             });
         }
 
-        let path = card
-            .path
-            .to_str()
-            .ok_or_else(|| Error::NonUtf8Path(card.path.to_path_buf()))?;
-
         if existing_card_hashes.contains(&hash) {
+            // the sides are rendered again when the deck configuration changes,
+            // and the card may have moved to another root
+            let root = card.root.as_ref();
             sqlx::query!(
                 "UPDATE card
-                SET compiled_at = ?
+                SET compiled_at = ?, term = ?, definition = ?, root = ?
                 WHERE hash = ?",
                 compiled_time,
+                card.term,
+                card.definition,
+                root,
                 hash,
             )
-            .execute(pool.as_ref())
+            .execute(&mut *tx)
             .await?;
         } else {
+            let root = card.root.as_ref();
             sqlx::query!(
-                "INSERT INTO card (hash, term, definition, source_path, compiled_at) VALUES (?, ?, ?, ?, ?)",
+                "INSERT INTO card (hash, term, definition, source_path, compiled_at, reverse_of, root) VALUES (?, ?, ?, ?, ?, ?, ?)",
                 hash,
                 card.term,
                 card.definition,
                 path,
                 compiled_time,
+                reverse_of,
+                root,
             )
-            .execute(pool.as_ref())
+            .execute(&mut *tx)
             .await?;
         }
 
@@ -321,7 +645,7 @@ async fn index(
     let topic_progress = progress.add(topic_progress);
 
     let existing_topics = sqlx::query!("SELECT hash, cards_hash FROM topic")
-        .fetch_all(pool.as_ref())
+        .fetch_all(&mut *tx)
         .await?
         .into_iter()
         .map(|record| (record.hash, record.cards_hash))
@@ -333,11 +657,7 @@ async fn index(
     {
         let name = data.name.as_ref();
 
-        let mut hasher = std::hash::DefaultHasher::new();
-        for hash in data.cards.iter().sorted() {
-            hash.hash(&mut hasher);
-        }
-        let cards_hash = i64::from_ne_bytes(hasher.finish().to_ne_bytes());
+        let cards_hash = topic_cards_hash(data.cards.iter().copied());
 
         // don't reinsert the topic if it is unchanged
         if existing_topics.contains(&(*topic, cards_hash)) {
@@ -350,30 +670,29 @@ async fn index(
         topic_progress.set_message(format!(": {}", data.full_name));
 
         sqlx::query!(
-            "INSERT OR REPLACE INTO topic (hash, name, parent, cards_hash) VALUES (?, ?, ?, ?)",
+            // topics can be shared with other roots, so replacing the row would drop their cards
+            "INSERT INTO topic (hash, name, parent, cards_hash) VALUES (?, ?, ?, ?)
+            ON CONFLICT (hash) DO UPDATE SET cards_hash = excluded.cards_hash",
             topic,
             name,
             data.parent,
             cards_hash,
         )
         .persistent(true)
-        .execute(pool.as_ref())
+        .execute(&mut *tx)
         .await?;
 
-        for card in data.cards.iter().copied() {
-            let pool = Arc::clone(pool);
-            let topic = *topic;
+        for cards in &data.cards.iter().chunks(CARD_TOPIC_BATCH_SIZE) {
+            let mut query = QueryBuilder::new("INSERT OR IGNORE INTO card_topic (card, topic) ");
+            let mut inserted = 0;
+            query.push_values(cards, |mut row, card| {
+                row.push_bind(card).push_bind(topic);
+                inserted += 1;
+            });
 
-            sqlx::query!(
-                "INSERT OR IGNORE INTO card_topic (card, topic) VALUES (?, ?)",
-                card,
-                topic,
-            )
-            .persistent(true)
-            .execute(pool.as_ref())
-            .await?;
+            query.build().persistent(true).execute(&mut *tx).await?;
 
-            topic_progress.inc(1);
+            topic_progress.inc(inserted);
         }
 
         index_progress.inc(1);
@@ -381,44 +700,125 @@ async fn index(
 
     progress.remove(&topic_progress);
     index_progress.set_message(": cleaning");
-    index_progress.set_length(2);
+    index_progress.set_length(roots.len() as u64 + 2);
     index_progress.set_position(0);
 
+    // only cards from the roots being rebuilt can be stale
+    for root in roots {
+        for key in root.keys()? {
+            sqlx::query!(
+                "DELETE FROM card WHERE root = ? AND compiled_at != ?",
+                key,
+                compiled_time
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        index_progress.inc(1);
+    }
+
     sqlx::query!(
         "DELETE FROM topic
-        WHERE topic.hash NOT IN (
-            SELECT card_topic.topic FROM card_topic
-            INNER JOIN card ON card_topic.card = card.hash
+        WHERE NOT EXISTS (
+            SELECT 1 FROM card_topic
             WHERE card_topic.topic = topic.hash
-            AND card.compiled_at = ?
-        )",
-        compiled_time
+        )"
     )
-    .execute(pool.as_ref())
+    .execute(&mut *tx)
     .await?;
 
     index_progress.inc(1);
 
-    sqlx::query!("DELETE FROM card WHERE compiled_at != ?", compiled_time)
-        .execute(pool.as_ref())
+    sqlx::query!(
+        "UPDATE rendered SET used_at = ?
+        WHERE hash IN (SELECT term FROM card UNION SELECT definition FROM card)",
+        compiled_time
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // everything but the most recently used orphans, which are kept in case their cards come back
+    let orphans = sqlx::query!(
+        r#"SELECT COUNT(*) AS "count!: i64", COALESCE(SUM(size), 0) AS "size!: i64"
+        FROM (
+            SELECT length(CAST(html AS BLOB)) + length(CAST(source AS BLOB)) + COALESCE(length(CAST(text AS BLOB)), 0) AS size
+            FROM rendered
+            WHERE hash NOT IN (SELECT term FROM card UNION SELECT definition FROM card)
+            ORDER BY used_at DESC
+            LIMIT -1 OFFSET ?
+        )"#,
+        keep_orphans
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "DELETE FROM rendered
+        WHERE hash IN (
+            SELECT hash FROM rendered
+            WHERE hash NOT IN (SELECT term FROM card UNION SELECT definition FROM card)
+            ORDER BY used_at DESC
+            LIMIT -1 OFFSET ?
+        )",
+        keep_orphans
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // assets are only kept for as long as a rendered source refers to them
+    let orphan_assets = sqlx::query!(
+        r#"SELECT COALESCE(SUM(length(data)), 0) AS "size!: i64"
+        FROM asset
+        WHERE hash NOT IN (SELECT asset FROM rendered_asset)"#
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!("DELETE FROM asset WHERE hash NOT IN (SELECT asset FROM rendered_asset)")
+        .execute(&mut *tx)
         .await?;
 
     index_progress.inc(1);
 
+    tx.commit().await?;
+
     progress.remove(&index_progress);
     _ = progress.println(section_title("Indexed", SectionTitleState::Done));
+    // the file only shrinks once the database is vacuumed, so this is not
+    // necessarily space given back to the filesystem
+    _ = progress.println(format!(
+        "{} {} unused renders, deleting {} of rendered data",
+        section_title("Collected", SectionTitleState::Done),
+        orphans.count,
+        HumanBytes(
+            (orphans.size + orphan_assets.size)
+                .try_into()
+                .unwrap_or_default()
+        ),
+    ));
 
     Ok(())
 }
 
-async fn run(pool: Arc<SqlitePool>, path: impl AsRef<Path>) -> Result<(), Error> {
+/// Build the decks in `roots`, returning the errors collected with `--keep-going`.
+async fn run(
+    pool: Arc<SqlitePool>,
+    renderer: Renderer,
+    roots: &[Root],
+    keep_going: bool,
+    keep_orphans: u32,
+) -> Result<Vec<Error>, Error> {
     let progress = MultiProgress::new();
+    let mut errors = CardErrors::new(keep_going);
 
-    let cards = load(&path, &progress).await?;
-    let cards = render(Arc::clone(&pool), cards, &progress).await?;
-    index(&pool, &cards, &progress).await?;
+    rekey::rekey(&pool, &progress).await?;
+    let cards = load(roots, &mut errors, &progress).await?;
+    let renderer = Arc::new(renderer);
+    let cards = render(Arc::clone(&pool), renderer, cards, &mut errors, &progress).await?;
+    index(&pool, roots, &cards, keep_orphans.into(), &progress).await?;
 
-    Ok(())
+    Ok(errors.errors)
 }
 
 fn report_error(err: impl Display) {
@@ -427,20 +827,140 @@ fn report_error(err: impl Display) {
 
 #[derive(Parser)]
 struct Cli {
-    #[arg(short, long, env = "DATABASE_URL")]
-    database_url: SqliteConnectOptions,
+    #[arg(
+        short,
+        long,
+        env = "DATABASE_URL",
+        required_unless_present = "render_worker"
+    )]
+    database_url: Option<SqliteConnectOptions>,
+    /// Directories of decks to build, each as `PATH` or `NAMESPACE=PATH` to put
+    /// every topic in it under a top-level `NAMESPACE` topic. A path containing
+    /// `=` can be given as `./PATH`
     #[arg(default_value = "data")]
-    input: PathBuf,
+    input: Vec<Root>,
+    /// Index every card that builds, and report all broken decks and cards at the end
+    #[arg(short, long)]
+    keep_going: bool,
+    /// Number of rendered sources no longer used by any card to keep, most recently used first
+    #[arg(long, default_value_t = 0)]
+    keep_orphans: u32,
+    /// Path to the `pdftocairo` program used to convert TeX output to SVG
+    #[arg(long, env = "PDFTOCAIRO", default_value = "pdftocairo")]
+    pdftocairo: PathBuf,
+    /// Local TeX bundle to render with, either a directory or a `.zip` file,
+    /// instead of downloading Tectonic's default bundle. `.tar` bundles need the
+    /// index Tectonic downloads alongside them, so unpack one into a directory
+    /// instead, and `.ttb` bundles are not supported
+    #[arg(long, env = "TEX_BUNDLE")]
+    tex_bundle: Option<PathBuf>,
+    /// Directory to cache TeX formats in, instead of Tectonic's default
+    #[arg(long, env = "TEX_FORMAT_CACHE")]
+    tex_format_cache: Option<PathBuf>,
+    /// Seconds that rendering a TeX or Typst source may take before it fails,
+    /// or 0 for no limit. Sources with a time or memory limit are rendered in
+    /// worker processes, which are killed when they exceed it
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    render_timeout: u64,
+    /// Megabytes of address space that rendering a TeX or Typst source may
+    /// use before it fails, or 0 for no limit
+    #[arg(long, value_name = "MEGABYTES", default_value_t = 0)]
+    render_memory_limit: u64,
+    /// Render TeX and Typst in worker processes even without a time or memory
+    /// limit, so that a source that crashes them only fails its card
+    #[arg(long)]
+    render_workers: bool,
+    /// Serve render requests on stdin and stdout, as one of the render workers
+    /// this program starts
+    #[arg(long, hide = true)]
+    render_worker: bool,
+}
+
+/// The arguments of a render worker, which renders like this process does.
+fn render_worker_args(cli: &Cli) -> Vec<OsString> {
+    let mut args = vec![
+        OsString::from("--render-worker"),
+        OsString::from("--pdftocairo"),
+        cli.pdftocairo.clone().into(),
+        OsString::from("--render-memory-limit"),
+        cli.render_memory_limit.to_string().into(),
+    ];
+    if let Some(bundle) = &cli.tex_bundle {
+        args.extend([OsString::from("--tex-bundle"), bundle.clone().into()]);
+    }
+    if let Some(format_cache) = &cli.tex_format_cache {
+        args.extend([
+            OsString::from("--tex-format-cache"),
+            format_cache.clone().into(),
+        ]);
+    }
+    args
+}
+
+/// Serve render requests with `renderer` until stdin ends, as a render worker.
+fn render_worker(renderer: &Renderer, memory_limit: u64) -> ExitCode {
+    if memory_limit > 0 {
+        let bytes = memory_limit.saturating_mul(1024 * 1024);
+        if let Err(err) = rlimit::setrlimit(rlimit::Resource::AS, bytes, bytes) {
+            report_error(format!(
+                "failed to limit the memory of render worker: {err}"
+            ));
+            return ExitCode::FAILURE;
+        }
+    }
+
+    match flashcards_render::render::worker::serve(
+        renderer,
+        std::io::stdin().lock(),
+        std::io::stdout().lock(),
+    ) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            report_error(format!("render worker failed: {err}"));
+            ExitCode::FAILURE
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> ExitCode {
     let cli = Cli::parse();
+    let worker_args = render_worker_args(&cli);
 
-    let pool = match SqlitePool::connect_with(cli.database_url)
-        .await
-        .map(Arc::new)
-    {
+    let mut renderer = Renderer::new()
+        .with_pdf_converter(PdfToCairo {
+            program: cli.pdftocairo,
+        })
+        .with_tex_options(TexOptions {
+            bundle: cli.tex_bundle,
+            format_cache: cli.tex_format_cache,
+        });
+
+    if cli.render_worker {
+        return render_worker(&renderer, cli.render_memory_limit);
+    }
+
+    if cli.render_workers || cli.render_timeout > 0 || cli.render_memory_limit > 0 {
+        let program = match std::env::current_exe() {
+            Ok(program) => program,
+            Err(err) => {
+                report_error(format!(
+                    "failed to find program to run render workers: {err}"
+                ));
+                return ExitCode::FAILURE;
+            }
+        };
+        let mut workers = WorkerPool::new(program, worker_args);
+        if cli.render_timeout > 0 {
+            workers = workers.with_time_limit(Duration::from_secs(cli.render_timeout));
+        }
+        renderer = renderer.with_workers(workers);
+    }
+
+    let database_url = cli
+        .database_url
+        .expect("database URL to be required unless running a render worker");
+    let pool = match SqlitePool::connect_with(database_url).await.map(Arc::new) {
         Ok(pool) => pool,
         Err(err) => {
             report_error(format!("failed to connect to database: {err}",));
@@ -453,10 +973,29 @@ async fn main() -> ExitCode {
         return ExitCode::FAILURE;
     }
 
-    if let Err(err) = run(pool, "data").await {
+    let errors = match run(pool, renderer, &cli.input, cli.keep_going, cli.keep_orphans).await {
+        Ok(errors) => errors,
+        Err(err) => {
+            report_error(err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if errors.is_empty() {
+        return ExitCode::SUCCESS;
+    }
+
+    for err in errors.iter() {
         report_error(err);
-        return ExitCode::FAILURE;
     }
 
-    ExitCode::SUCCESS
+    let (decks, cards) = errors.iter().fold((0, 0), |(decks, cards), err| match err {
+        Error::Load(_) | Error::Deserialize(_) => (decks + 1, cards),
+        _ => (decks, cards + 1),
+    });
+    report_error(format!(
+        "{decks} deck(s) failed to load and {cards} card(s) failed to render, the rest were indexed"
+    ));
+
+    ExitCode::FAILURE
 }
@@ -0,0 +1,224 @@
+//! Migration of databases built with an older [`HASH_VERSION`].
+//!
+//! Topics can be rekeyed from their path, and cards from the content of their
+//! sides, source path and topics. Review state is moved along with the cards
+//! it belongs to. Assets keep their hashes, since rendered HTML refers to them
+//! by URL.
+//!
+//! Rendering is best effort, as the deck configuration a source was rendered
+//! with is not stored: rendered sources are rekeyed as if they were rendered
+//! with the default configuration. Those that weren't are rendered again by
+//! the next build, and their stale renders are collected with other unused
+//! ones.
+
+use crate::{Error, SectionTitleState, card_hash, section_title, topic_cards_hash};
+use flashcards_render::hash::HASH_VERSION;
+use flashcards_render::{CardKind, Source, Topic};
+use indicatif::MultiProgress;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+fn topic_path(topics: &HashMap<i64, (String, Option<i64>)>, mut hash: i64) -> Option<Topic> {
+    let mut segments = Vec::new();
+    loop {
+        let (name, parent) = topics.get(&hash)?;
+        segments.push(Arc::from(name.as_str()));
+        match parent {
+            Some(parent) => hash = *parent,
+            None => break,
+        }
+    }
+    segments.reverse();
+    Some(Topic(segments))
+}
+
+pub async fn rekey(pool: &SqlitePool, progress: &MultiProgress) -> Result<(), Error> {
+    let version = sqlx::query!("SELECT version FROM hash_version")
+        .fetch_optional(pool)
+        .await?
+        .map_or(0, |record| record.version);
+
+    if version == HASH_VERSION {
+        return Ok(());
+    }
+
+    let mut tx = pool.begin().await?;
+
+    // keys are updated one table at a time, so references are only consistent again at the end
+    sqlx::query("PRAGMA defer_foreign_keys = ON")
+        .execute(&mut *tx)
+        .await?;
+
+    let mut rendered = HashMap::new();
+    for record in sqlx::query!("SELECT hash, render_format, source FROM rendered")
+        .fetch_all(&mut *tx)
+        .await?
+    {
+        let source = Source {
+            format: record.render_format.parse()?,
+            source: record.source,
+            config: Default::default(),
+        };
+        rendered.insert(record.hash, (source.content_hash(), source.render_hash()));
+    }
+
+    let topics = sqlx::query!(r#"SELECT hash AS "hash!", name, parent FROM topic"#)
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(|record| (record.hash, (record.name, record.parent)))
+        .collect::<HashMap<_, _>>();
+
+    let topic_keys = topics
+        .keys()
+        .filter_map(|hash| Some((*hash, topic_path(&topics, *hash)?.content_hash())))
+        .collect::<HashMap<_, _>>();
+
+    let mut card_topics = HashMap::<i64, Vec<i64>>::new();
+    for record in sqlx::query!(r#"SELECT card, topic AS "topic: i64" FROM card_topic"#)
+        .fetch_all(&mut *tx)
+        .await?
+    {
+        card_topics
+            .entry(record.card)
+            .or_default()
+            .push(record.topic);
+    }
+
+    let mut cards = HashMap::new();
+    for record in sqlx::query!("SELECT hash, term, definition, source_path, reverse_of FROM card")
+        .fetch_all(&mut *tx)
+        .await?
+    {
+        let (term_content, term) = rendered
+            .get(&record.term)
+            .copied()
+            .unwrap_or((record.term, record.term));
+        let (definition_content, definition) = rendered
+            .get(&record.definition)
+            .copied()
+            .unwrap_or((record.definition, record.definition));
+        let topics = card_topics
+            .get(&record.hash)
+            .into_iter()
+            .flatten()
+            .filter_map(|topic| topic_keys.get(topic).copied());
+
+        // only reversed cards are linked to another card, and cloze cards are
+        // hashed like basic ones
+        let kind = match record.reverse_of {
+            Some(_) => CardKind::Reversed,
+            None => CardKind::Basic,
+        };
+
+        let hash = card_hash(
+            term_content,
+            definition_content,
+            &record.source_path,
+            kind,
+            topics,
+        );
+        cards.insert(record.hash, (hash, term, definition));
+    }
+
+    let mut topic_cards = HashMap::<i64, Vec<i64>>::new();
+    for (card, topics) in card_topics.iter() {
+        let Some((card, ..)) = cards.get(card) else {
+            continue;
+        };
+
+        for topic in topics {
+            topic_cards.entry(*topic).or_default().push(*card);
+        }
+    }
+
+    for (old, (_, new)) in rendered.iter() {
+        sqlx::query!("UPDATE rendered SET hash = ? WHERE hash = ?", new, old)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!(
+            "UPDATE rendered_asset SET rendered = ? WHERE rendered = ?",
+            new,
+            old
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    for (old, (_, parent)) in topics.iter() {
+        let Some(new) = topic_keys.get(old) else {
+            continue;
+        };
+        let parent = parent.and_then(|parent| topic_keys.get(&parent).copied());
+        let cards_hash = topic_cards_hash(topic_cards.remove(old).unwrap_or_default());
+
+        sqlx::query!(
+            "UPDATE topic SET hash = ?, parent = ?, cards_hash = ? WHERE hash = ?",
+            new,
+            parent,
+            cards_hash,
+            old,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    for (old, (new, term, definition)) in cards.iter() {
+        sqlx::query!(
+            "UPDATE card SET hash = ?, term = ?, definition = ? WHERE hash = ?",
+            new,
+            term,
+            definition,
+            old,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "UPDATE card SET reverse_of = ? WHERE reverse_of = ?",
+            new,
+            old
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!("UPDATE card_topic SET card = ? WHERE card = ?", new, old)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!("UPDATE review SET card = ? WHERE card = ?", new, old)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!("UPDATE schedule SET card = ? WHERE card = ?", new, old)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    for (old, new) in topic_keys.iter() {
+        sqlx::query!("UPDATE card_topic SET topic = ? WHERE topic = ?", new, old)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    sqlx::query!("DELETE FROM hash_version")
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query!(
+        "INSERT INTO hash_version (version) VALUES (?)",
+        HASH_VERSION
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    if !rendered.is_empty() {
+        _ = progress.println(section_title("Rekeyed", SectionTitleState::Done));
+    }
+
+    Ok(())
+}
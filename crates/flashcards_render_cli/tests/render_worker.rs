@@ -0,0 +1,58 @@
+use flashcards_render::render::worker::WorkerPool;
+use flashcards_render::render::{Error, Renderer};
+use flashcards_render::{Format, Source};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+fn source(text: &str, format: Format) -> Source {
+    Source {
+        source: text.to_string(),
+        format,
+        config: Default::default(),
+    }
+}
+
+#[test]
+fn aborting_source_fails_alone() {
+    let spawned =
+        std::env::temp_dir().join(format!("flashcards-render-worker-{}", std::process::id()));
+    _ = std::fs::remove_file(&spawned);
+
+    // the worker is started through a shell which notes every start, to see
+    // that a crashing source is retried in a new worker
+    let workers = WorkerPool::new(
+        "sh",
+        [
+            "-c".as_ref(),
+            r#"echo >> "$1"; exec "$0" --render-worker --render-memory-limit 512"#.as_ref(),
+            env!("CARGO_BIN_EXE_flashcards_render_cli").as_ref(),
+            spawned.as_os_str(),
+        ],
+    )
+    .with_time_limit(Duration::from_secs(60));
+    let renderer = Renderer::new().with_workers(workers);
+
+    // a string more than the limit, which is allocated all at once, so the
+    // worker aborts as soon as it is rendered
+    let rendered = renderer.render_many(vec![
+        (source("*hi*", Format::Markdown), PathBuf::from("hi.toml")),
+        (
+            source(r#"#("x" * 2000000000).len()"#, Format::Typst),
+            PathBuf::from("repeat.toml"),
+        ),
+    ]);
+    let starts = std::fs::read_to_string(&spawned).unwrap().lines().count();
+    std::fs::remove_file(&spawned).unwrap();
+
+    assert_eq!(
+        rendered[0].as_ref().unwrap().html,
+        "<div><p><em>hi</em></p>\n</div>"
+    );
+    assert!(matches!(
+        &rendered[1],
+        Err(Error::WorkerCrashed { path, .. }) if path == Path::new("repeat.toml")
+    ));
+    // one for the batch, which aborts, one which renders the Markdown source
+    // and then aborts on the Typst source, and one in which that is retried
+    assert_eq!(starts, 3);
+}
@@ -2,16 +2,20 @@
 #![feature(int_roundings)]
 
 use askama::Template;
+use chrono::Utc;
 use poem::EndpointExt;
 use poem::http::{HeaderMap, HeaderValue, StatusCode};
 use poem::middleware::AddData;
-use poem::web::{Data, Path, Query};
+use poem::web::{Data, Form, Path, Query};
+use scheduler::{Grade, Schedule};
 use serde::Deserialize;
-use sqlx::SqlitePool;
 use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::{SqliteExecutor, SqlitePool};
 use std::str::FromStr;
 use std::sync::Arc;
 
+mod scheduler;
+
 const STYLE: &'static str = include_str!(concat!(env!("OUT_DIR"), "/style.css"));
 const SCRIPT: &'static str = include_str!(concat!(env!("OUT_DIR"), "/main.js"));
 
@@ -100,6 +104,141 @@ async fn index(
     }
 }
 
+#[derive(Debug)]
+struct SearchResult {
+    card: Card,
+    hash: i64,
+    snippet: String,
+    topics: Vec<NamedHash>,
+}
+
+#[derive(Debug, askama::Template)]
+#[template(path = "search.html")]
+struct SearchPage {
+    results: Vec<SearchResult>,
+    query: String,
+}
+
+impl TemplateResponse for SearchPage {}
+
+#[derive(Debug, askama::Template)]
+#[template(path = "search.html", block = "search_results")]
+struct SearchPageResults {
+    results: Vec<SearchResult>,
+}
+
+impl TemplateResponse for SearchPageResults {}
+
+const SEARCH_RESULTS: i64 = 50;
+const HIGHLIGHT_START: &str = "\u{2}";
+const HIGHLIGHT_END: &str = "\u{3}";
+
+/// Turn user input into an FTS5 query matching every word, the last as a prefix.
+fn fts_query(query: &str) -> Option<String> {
+    let words = query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>();
+
+    if words.is_empty() {
+        None
+    } else {
+        Some(format!("{}*", words.join(" ")))
+    }
+}
+
+/// Escape a snippet from the search index, turning the highlight markers into `<mark>`s.
+fn highlight_snippet(snippet: &str) -> String {
+    let mut html = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            _ => html.push(c),
+        }
+    }
+    html.replace(HIGHLIGHT_START, "<mark>")
+        .replace(HIGHLIGHT_END, "</mark>")
+}
+
+#[test]
+fn search_query_escaping() {
+    assert_eq!(fts_query("  "), None);
+    assert_eq!(
+        fts_query(r#"acid "base"#).as_deref(),
+        Some(r#""acid" """base"*"#)
+    );
+    assert_eq!(
+        highlight_snippet("\u{2}<b>\u{3} & c"),
+        "<mark>&lt;b&gt;</mark> &amp; c"
+    );
+}
+
+#[poem::handler]
+async fn search(
+    pool: Data<&Arc<SqlitePool>>,
+    query: Query<SearchQuery>,
+    headers: &HeaderMap,
+) -> poem::Response {
+    let query = query.q.clone().unwrap_or_default();
+
+    let matches = match fts_query(&query) {
+        Some(fts_query) => sqlx::query!(
+            r#"WITH matches AS (
+                SELECT rowid AS rendered, rank, snippet(rendered_search, 0, ?, ?, '…', 16) AS snippet
+                FROM rendered_search
+                WHERE rendered_search MATCH ?
+            )
+            SELECT card.hash AS "hash!", term.html AS "term!", definition.html AS "definition!",
+                matches.snippet AS "snippet!: String", MIN(matches.rank) AS "rank!: f64"
+            FROM matches
+            INNER JOIN card ON card.term = matches.rendered OR card.definition = matches.rendered
+            INNER JOIN rendered AS term ON card.term = term.hash
+            INNER JOIN rendered AS definition ON card.definition = definition.hash
+            GROUP BY card.hash
+            ORDER BY MIN(matches.rank)
+            LIMIT ?"#,
+            HIGHLIGHT_START,
+            HIGHLIGHT_END,
+            fts_query,
+            SEARCH_RESULTS,
+        )
+        .fetch_all(pool.as_ref())
+        .await,
+        None => Ok(Vec::new()),
+    };
+
+    let Ok(matches) = matches else {
+        return internal_error();
+    };
+
+    let mut results = Vec::with_capacity(matches.len());
+    for result in matches {
+        let Ok(topics) = card_topics(&pool, result.hash).await else {
+            return internal_error();
+        };
+
+        results.push(SearchResult {
+            card: Card {
+                term: result.term,
+                definition: result.definition,
+            },
+            hash: result.hash,
+            snippet: highlight_snippet(&result.snippet),
+            topics,
+        });
+    }
+
+    if headers.get("hx-partial") == Some(&HeaderValue::from_static("true")) {
+        SearchPageResults { results }.into_response()
+    } else {
+        SearchPage { results, query }.into_response()
+    }
+}
+
 #[derive(Debug)]
 struct Card {
     term: String,
@@ -146,6 +285,26 @@ async fn topic_ancestors(pool: &SqlitePool, topic: i64) -> sqlx::Result<Vec<Name
     .await
 }
 
+/// The full names of the topics a card is in.
+async fn card_topics(pool: &SqlitePool, card: i64) -> sqlx::Result<Vec<NamedHash>> {
+    sqlx::query_as!(
+        NamedHash,
+        r#"WITH RECURSIVE ancestors AS (
+            SELECT parent, name, name AS full_name, hash AS start_hash FROM topic
+            UNION ALL
+            SELECT topic.parent, ancestors.name, topic.name || '/' || ancestors.full_name AS full_name, ancestors.start_hash
+            FROM topic JOIN ancestors ON topic.hash = ancestors.parent
+        )
+        SELECT start_hash AS "hash!", ancestors.full_name AS "name!: String"
+        FROM ancestors
+        INNER JOIN card_topic ON start_hash = card_topic.topic
+        WHERE ancestors.parent IS NULL AND card_topic.card = ?"#,
+        card,
+    )
+    .fetch_all(pool)
+    .await
+}
+
 #[derive(Deserialize)]
 struct ViewQuery {
     page: Option<i64>,
@@ -221,13 +380,28 @@ async fn view(
     .into_response()
 }
 
+#[derive(Debug)]
+struct GradeButton {
+    grade: i64,
+    name: &'static str,
+    interval: String,
+}
+
+#[derive(Debug)]
+struct StudyCard {
+    card: Card,
+    hash: i64,
+    topics: Vec<NamedHash>,
+    grades: Vec<GradeButton>,
+}
+
 #[derive(Debug, askama::Template)]
 #[template(path = "study.html")]
 struct Study {
-    card: Card,
+    card: Option<StudyCard>,
     total_cards: i64,
-    index: i64,
-    topics: Vec<NamedHash>,
+    due_cards: i64,
+    new_cards: i64,
     topic_ancestors: Vec<NamedHash>,
     topic_hash: i64,
 }
@@ -236,7 +410,18 @@ impl TemplateResponse for Study {}
 
 #[derive(Deserialize)]
 struct StudyQuery {
-    index: Option<i64>,
+    card: Option<i64>,
+}
+
+async fn card_schedule(executor: impl SqliteExecutor<'_>, card: i64) -> sqlx::Result<Schedule> {
+    Ok(sqlx::query_as!(
+        Schedule,
+        "SELECT ease, interval_days, repetitions FROM schedule WHERE card = ?",
+        card
+    )
+    .fetch_optional(executor)
+    .await?
+    .unwrap_or_default())
 }
 
 #[poem::handler]
@@ -249,15 +434,17 @@ async fn study(
         return internal_error();
     };
 
-    let Some(idx) = query.index else {
-        return poem::Response::builder()
-            .status(StatusCode::TEMPORARY_REDIRECT)
-            .header("location", format!("/study/{}?index={}", topic_hash, 0))
-            .body(());
-    };
-
-    let Ok(total_cards) = sqlx::query!(
-        "SELECT COUNT(card) AS total_cards FROM card_topic WHERE card_topic.topic = ?",
+    let now = Utc::now();
+
+    let Ok(counts) = sqlx::query!(
+        r#"SELECT
+            COUNT(card_topic.card) AS "total_cards!: i64",
+            COUNT(schedule.card) FILTER (WHERE schedule.due_at <= ?) AS "due_cards!: i64",
+            COUNT(card_topic.card) FILTER (WHERE schedule.card IS NULL) AS "new_cards!: i64"
+        FROM card_topic
+        LEFT JOIN schedule ON schedule.card = card_topic.card
+        WHERE card_topic.topic = ?"#,
+        now,
         topic_hash
     )
     .fetch_one(pool.as_ref())
@@ -266,58 +453,235 @@ async fn study(
         return internal_error();
     };
 
+    // overdue cards first, oldest first, followed by cards that have never been reviewed
     let Ok(card) = sqlx::query!(
         "SELECT term.html AS term, definition.html AS definition, card.hash FROM card
         INNER JOIN rendered AS term ON card.term = term.hash
         INNER JOIN rendered AS definition ON card.definition = definition.hash
         INNER JOIN card_topic ON card_topic.card = card.hash
+        LEFT JOIN schedule ON schedule.card = card.hash
         WHERE card_topic.topic = ?
-        GROUP BY card.hash
-        LIMIT 1
-        OFFSET ?",
+        AND (card.hash = ? OR (? IS NULL AND (schedule.due_at IS NULL OR schedule.due_at <= ?)))
+        ORDER BY schedule.due_at IS NULL, schedule.due_at, card.hash
+        LIMIT 1",
         topic_hash,
-        idx
+        query.card,
+        query.card,
+        now,
     )
-    .fetch_one(pool.as_ref())
+    .fetch_optional(pool.as_ref())
     .await
     else {
         return internal_error();
     };
 
-    let Ok(topics) = sqlx::query_as!(
-        NamedHash,
-        r#"WITH RECURSIVE ancestors AS (
-            SELECT parent, name, name AS full_name, hash AS start_hash FROM topic
-            UNION ALL
-            SELECT topic.parent, ancestors.name, topic.name || '/' || ancestors.full_name AS full_name, ancestors.start_hash
-            FROM topic JOIN ancestors ON topic.hash = ancestors.parent
-        )
-        SELECT start_hash AS "hash!", ancestors.full_name AS "name!: String"
-        FROM ancestors
-        INNER JOIN card_topic ON start_hash = card_topic.topic
-        WHERE ancestors.parent IS NULL AND card_topic.card = ?"#,
-        card.hash,
-    )
-    .fetch_all(pool.as_ref())
-    .await
-    else {
-        return internal_error();
+    let card = match card {
+        Some(card) => {
+            let Ok(topics) = card_topics(&pool, card.hash).await else {
+                return internal_error();
+            };
+
+            let Ok(schedule) = card_schedule(pool.as_ref(), card.hash).await else {
+                return internal_error();
+            };
+
+            let grades = Grade::ALL
+                .into_iter()
+                .map(|grade| GradeButton {
+                    grade: grade as i64,
+                    name: grade.name(),
+                    interval: scheduler::format_interval(schedule.review(grade).interval()),
+                })
+                .collect();
+
+            Some(StudyCard {
+                card: Card {
+                    term: card.term,
+                    definition: card.definition,
+                },
+                hash: card.hash,
+                topics,
+                grades,
+            })
+        }
+        None => None,
     };
 
     Study {
-        card: Card {
-            term: card.term,
-            definition: card.definition,
-        },
-        total_cards: total_cards.total_cards,
-        index: idx,
+        card,
+        total_cards: counts.total_cards,
+        due_cards: counts.due_cards,
+        new_cards: counts.new_cards,
         topic_ancestors,
         topic_hash,
-        topics,
     }
     .into_response()
 }
 
+#[derive(Deserialize)]
+struct ReviewForm {
+    card: i64,
+    grade: i64,
+}
+
+#[poem::handler]
+async fn review(
+    pool: Data<&Arc<SqlitePool>>,
+    Path(topic_hash): Path<i64>,
+    Form(form): Form<ReviewForm>,
+) -> poem::Response {
+    let Ok(grade) = Grade::try_from(form.grade) else {
+        return poem::Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .finish();
+    };
+
+    let Ok(mut tx) = pool.begin().await else {
+        return internal_error();
+    };
+
+    // reviews and schedules have no foreign key, so that they outlive the card
+    // being rebuilt, which makes this the only check that the card exists
+    match sqlx::query!(
+        "SELECT card FROM card_topic WHERE card = ? AND topic = ?",
+        form.card,
+        topic_hash
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    {
+        Ok(Some(_)) => {}
+        Ok(None) => return StatusCode::NOT_FOUND.into(),
+        Err(_) => return internal_error(),
+    }
+
+    let Ok(schedule) = card_schedule(&mut *tx, form.card).await else {
+        return internal_error();
+    };
+
+    let reviewed_at = Utc::now();
+    let schedule = schedule.review(grade);
+    let due_at = schedule.due_at(reviewed_at);
+    let grade = grade as i64;
+
+    if sqlx::query!(
+        "INSERT INTO review (card, grade, reviewed_at) VALUES (?, ?, ?)",
+        form.card,
+        grade,
+        reviewed_at,
+    )
+    .execute(&mut *tx)
+    .await
+    .is_err()
+    {
+        return internal_error();
+    }
+
+    if sqlx::query!(
+        "INSERT OR REPLACE INTO schedule (card, ease, interval_days, repetitions, due_at) VALUES (?, ?, ?, ?, ?)",
+        form.card,
+        schedule.ease,
+        schedule.interval_days,
+        schedule.repetitions,
+        due_at,
+    )
+    .execute(&mut *tx)
+    .await
+    .is_err()
+    {
+        return internal_error();
+    }
+
+    if tx.commit().await.is_err() {
+        return internal_error();
+    }
+
+    poem::Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header("location", format!("/study/{topic_hash}"))
+        .finish()
+}
+
+/// Whether an `If-None-Match` header value, a list of entity tags or `*`,
+/// matches `etag`. Weak tags match their strong counterpart, as this is a weak
+/// comparison.
+fn none_match(value: &str, etag: &str) -> bool {
+    let mut rest = value.trim();
+    if rest == "*" {
+        return true;
+    }
+
+    while !rest.is_empty() {
+        let tag = rest.strip_prefix("W/").unwrap_or(rest);
+        let Some(len) = tag.strip_prefix('"').and_then(|tag| tag.find('"')) else {
+            return false;
+        };
+        if tag[..len + 2] == *etag {
+            return true;
+        }
+        rest = tag[len + 2..].trim_start_matches([' ', '\t', ',']);
+    }
+
+    false
+}
+
+#[test]
+fn none_match_parses_lists() {
+    let etag = r#""00000000000000ff""#;
+    for value in [
+        r#""00000000000000ff""#,
+        r#""a", "00000000000000ff""#,
+        r#"W/"00000000000000ff""#,
+        r#""a",W/"00000000000000ff", "b""#,
+        "*",
+    ] {
+        assert!(none_match(value, etag), "{value}");
+    }
+    for value in [
+        r#""a", "b""#,
+        r#""00000000000000f""#,
+        "",
+        "00000000000000ff",
+    ] {
+        assert!(!none_match(value, etag), "{value}");
+    }
+}
+
+/// Serve a rendered asset, which never changes since it is keyed by the hash
+/// of its contents.
+#[poem::handler]
+async fn asset(
+    pool: Data<&Arc<SqlitePool>>,
+    Path(hash): Path<i64>,
+    headers: &HeaderMap,
+) -> poem::Response {
+    use poem::http::header;
+
+    let etag = format!("\"{hash:016x}\"");
+    if headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .any(|value| value.to_str().is_ok_and(|value| none_match(value, &etag)))
+    {
+        return StatusCode::NOT_MODIFIED.into();
+    }
+
+    let asset = match sqlx::query!("SELECT mime, data FROM asset WHERE hash = ?", hash)
+        .fetch_optional(pool.as_ref())
+        .await
+    {
+        Ok(Some(asset)) => asset,
+        Ok(None) => return StatusCode::NOT_FOUND.into(),
+        Err(_) => return internal_error(),
+    };
+
+    poem::Response::builder()
+        .header(header::CONTENT_TYPE, asset.mime)
+        .header(header::ETAG, etag)
+        .header(header::CACHE_CONTROL, "public, max-age=31536000, immutable")
+        .body(asset.data)
+}
+
 #[derive(rust_embed::RustEmbed)]
 #[folder = "$OUT_DIR/static"]
 struct KatexAsset;
@@ -367,15 +731,16 @@ impl poem::Endpoint for KatexAsset {
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let database_url = std::env::var("DATABASE_URL")?;
-    let pool = Arc::new(
-        SqlitePool::connect_with(SqliteConnectOptions::from_str(&database_url)?.read_only(true))
-            .await?,
-    );
+    let pool =
+        Arc::new(SqlitePool::connect_with(SqliteConnectOptions::from_str(&database_url)?).await?);
 
     let app = poem::Route::new()
         .at("/", poem::get(index))
+        .at("/search", poem::get(search))
         .at("/view/:hash", poem::get(view))
         .at("/study/:hash", poem::get(study))
+        .at("/study/:hash/review", poem::post(review))
+        .at("/asset/:hash", poem::get(asset))
         .nest("/static", KatexAsset)
         .with(AddData::new(pool));
 
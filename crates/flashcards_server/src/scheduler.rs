@@ -0,0 +1,150 @@
+use chrono::{DateTime, TimeDelta, Utc};
+
+const MINIMUM_EASE: f64 = 1.3;
+const INITIAL_EASE: f64 = 2.5;
+/// Lapsed cards come back after ten minutes.
+const LAPSE_INTERVAL_DAYS: f64 = 10.0 / (24.0 * 60.0);
+
+/// How well a card was recalled, as chosen by the "Again / Hard / Good / Easy" buttons.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Grade {
+    Again = 1,
+    Hard = 2,
+    Good = 3,
+    Easy = 4,
+}
+
+impl Grade {
+    pub const ALL: [Self; 4] = [Self::Again, Self::Hard, Self::Good, Self::Easy];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Again => "Again",
+            Self::Hard => "Hard",
+            Self::Good => "Good",
+            Self::Easy => "Easy",
+        }
+    }
+
+    /// The SM-2 response quality (0-5) this grade corresponds to.
+    fn quality(self) -> f64 {
+        match self {
+            Self::Again => 1.0,
+            Self::Hard => 3.0,
+            Self::Good => 4.0,
+            Self::Easy => 5.0,
+        }
+    }
+}
+
+impl TryFrom<i64> for Grade {
+    type Error = i64;
+
+    fn try_from(value: i64) -> Result<Self, Self::Error> {
+        Self::ALL
+            .into_iter()
+            .find(|grade| *grade as i64 == value)
+            .ok_or(value)
+    }
+}
+
+/// SM-2 scheduling state of a single card.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Schedule {
+    pub ease: f64,
+    pub interval_days: f64,
+    pub repetitions: i64,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self {
+            ease: INITIAL_EASE,
+            interval_days: 0.0,
+            repetitions: 0,
+        }
+    }
+}
+
+impl Schedule {
+    /// Apply a review, returning the new state.
+    ///
+    /// This is SM-2, except that lapsed cards are due again after a few
+    /// minutes so they come back in the same session, and "Hard" and "Easy"
+    /// scale the interval down and up respectively.
+    #[must_use]
+    pub fn review(self, grade: Grade) -> Self {
+        let q = 5.0 - grade.quality();
+        let ease = (self.ease + 0.1 - q * (0.08 + q * 0.02)).max(MINIMUM_EASE);
+
+        if grade == Grade::Again {
+            return Self {
+                ease,
+                interval_days: LAPSE_INTERVAL_DAYS,
+                repetitions: 0,
+            };
+        }
+
+        let interval_days = match self.repetitions {
+            0 => 1.0,
+            1 => 6.0,
+            _ => self.interval_days * ease,
+        };
+
+        let interval_days = match grade {
+            Grade::Hard => (interval_days * 0.6).max(1.0),
+            Grade::Easy => interval_days * 1.3,
+            Grade::Again | Grade::Good => interval_days,
+        };
+
+        Self {
+            ease,
+            interval_days,
+            repetitions: self.repetitions + 1,
+        }
+    }
+
+    pub fn interval(&self) -> TimeDelta {
+        #[allow(clippy::cast_possible_truncation)]
+        TimeDelta::try_seconds((self.interval_days * 86_400.0).round() as i64)
+            .unwrap_or(TimeDelta::MAX)
+    }
+
+    pub fn due_at(&self, reviewed_at: DateTime<Utc>) -> DateTime<Utc> {
+        reviewed_at
+            .checked_add_signed(self.interval())
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+    }
+}
+
+/// Short human-readable form of an interval, e.g. `10m` or `6d`.
+pub fn format_interval(interval: TimeDelta) -> String {
+    if interval < TimeDelta::hours(1) {
+        format!("{}m", interval.num_minutes().max(1))
+    } else if interval < TimeDelta::days(1) {
+        format!("{}h", interval.num_hours())
+    } else if interval < TimeDelta::days(365) {
+        format!("{}d", interval.num_days())
+    } else {
+        format!("{}y", interval.num_days() / 365)
+    }
+}
+
+#[test]
+#[allow(clippy::float_cmp)]
+fn sm2_intervals() {
+    let schedule = Schedule::default().review(Grade::Good);
+    assert_eq!(schedule.interval_days, 1.0);
+
+    let schedule = schedule.review(Grade::Good);
+    assert_eq!(schedule.interval_days, 6.0);
+
+    let schedule = schedule.review(Grade::Good);
+    assert_eq!(schedule.interval_days, 6.0 * INITIAL_EASE);
+
+    let lapsed = schedule.review(Grade::Again);
+    assert_eq!(lapsed.repetitions, 0);
+    assert_eq!(lapsed.interval_days, LAPSE_INTERVAL_DAYS);
+    assert!(lapsed.ease < schedule.ease);
+    assert!(lapsed.ease >= MINIMUM_EASE);
+}
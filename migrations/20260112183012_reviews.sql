@@ -0,0 +1,20 @@
+-- Review state is keyed by card hash without a foreign key, so that it
+-- survives `flashcards_render_cli` deleting and reinserting cards.
+
+CREATE TABLE review (
+	card INTEGER NOT NULL,
+	grade INTEGER NOT NULL,
+	reviewed_at DATETIME NOT NULL
+);
+
+CREATE INDEX review_card ON review (card);
+
+CREATE TABLE schedule (
+	card INTEGER PRIMARY KEY,
+	ease REAL NOT NULL,
+	interval_days REAL NOT NULL,
+	repetitions INTEGER NOT NULL,
+	due_at DATETIME NOT NULL
+);
+
+CREATE INDEX schedule_due_at ON schedule (due_at);
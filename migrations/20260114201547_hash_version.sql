@@ -0,0 +1,7 @@
+-- The version of `flashcards_render::hash` used to compute the hashes in
+-- `rendered`, `topic` and `card`. A database without a row was built with
+-- the unstable `std::hash::DefaultHasher` and has to be rekeyed.
+
+CREATE TABLE hash_version (
+	version INTEGER NOT NULL
+);
@@ -0,0 +1,24 @@
+-- A plain-text projection of each rendered source, filled in by
+-- `flashcards_render_cli`, and a full-text index over it kept in sync by
+-- triggers.
+
+ALTER TABLE rendered ADD COLUMN text TEXT;
+
+CREATE VIRTUAL TABLE rendered_search USING fts5 (
+	text,
+	content = 'rendered',
+	content_rowid = 'hash'
+);
+
+CREATE TRIGGER rendered_search_insert AFTER INSERT ON rendered BEGIN
+	INSERT INTO rendered_search (rowid, text) VALUES (new.hash, new.text);
+END;
+
+CREATE TRIGGER rendered_search_delete AFTER DELETE ON rendered BEGIN
+	INSERT INTO rendered_search (rendered_search, rowid, text) VALUES ('delete', old.hash, old.text);
+END;
+
+CREATE TRIGGER rendered_search_update AFTER UPDATE ON rendered BEGIN
+	INSERT INTO rendered_search (rendered_search, rowid, text) VALUES ('delete', old.hash, old.text);
+	INSERT INTO rendered_search (rowid, text) VALUES (new.hash, new.text);
+END;
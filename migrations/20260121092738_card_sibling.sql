@@ -0,0 +1,3 @@
+-- The card that a generated card, such as a reversed card, is a sibling of.
+
+ALTER TABLE card ADD COLUMN sibling INTEGER;
@@ -0,0 +1,5 @@
+-- The input directory a card was loaded from, so that rebuilding one deck
+-- root only removes stale cards from that root. Cards indexed before multiple
+-- roots were supported were always loaded from `data`.
+
+ALTER TABLE card ADD COLUMN root TEXT NOT NULL DEFAULT 'data';
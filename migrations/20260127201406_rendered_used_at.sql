@@ -0,0 +1,13 @@
+-- When a rendered source was last referenced by a card, so that the most
+-- recently used unreferenced sources can be kept as a cache. Every build
+-- updates it, so the search index is only updated when the indexed columns
+-- change.
+
+ALTER TABLE rendered ADD COLUMN used_at DATETIME;
+
+DROP TRIGGER rendered_search_update;
+
+CREATE TRIGGER rendered_search_update AFTER UPDATE OF hash, text ON rendered BEGIN
+	INSERT INTO rendered_search (rendered_search, rowid, text) VALUES ('delete', old.hash, old.text);
+	INSERT INTO rendered_search (rowid, text) VALUES (new.hash, new.text);
+END;
@@ -0,0 +1,18 @@
+-- Files referenced by rendered HTML, like SVG diagrams, keyed by the hash of
+-- their contents so that they can be served with immutable cache headers.
+
+CREATE TABLE asset (
+	hash INTEGER PRIMARY KEY,
+	mime TEXT NOT NULL,
+	data BLOB NOT NULL
+);
+
+CREATE TABLE rendered_asset (
+	rendered INTEGER NOT NULL,
+	asset INTEGER NOT NULL,
+	FOREIGN KEY (rendered) REFERENCES rendered (hash) ON DELETE CASCADE,
+	FOREIGN KEY (asset) REFERENCES asset (hash),
+	PRIMARY KEY (rendered, asset)
+);
+
+CREATE INDEX rendered_asset_asset ON rendered_asset (asset);
@@ -0,0 +1,2 @@
+-- Typst cards exported as HTML rather than rendered as images
+INSERT INTO render_format (name) VALUES ("typst-html");
@@ -0,0 +1,5 @@
+-- Only reversed cards are linked to the card they were generated from; cloze
+-- cards are not linked to their siblings, so the column is named for what it
+-- holds.
+
+ALTER TABLE card RENAME COLUMN sibling TO reverse_of;